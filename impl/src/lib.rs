@@ -5,21 +5,55 @@ extern crate quote;
 use proc_macro::TokenStream;
 use syn::{parse_macro_input, GenericArgument};
 
+const USAGE: &str =
+    "Benchmark file must be annotated with #[config(path = \"/path/to/file.toml\")]";
+
 #[proc_macro_attribute]
 pub fn config(args: TokenStream, input: TokenStream) -> TokenStream {
-    let args = parse_macro_input!(args as syn::AttributeArgs)
-        .first()
-        .expect("Benchmark file must be annotated with #[config(path = \"/path/to/file.toml\")]")
-        .clone();
-    let file_path = get_config_file_path(&args)
-        .expect("Benchmark file must be annotated with #[config(path = \"/path/to/file.toml\")]");
-
+    let args = parse_macro_input!(args as syn::AttributeArgs);
     let ty: syn::Item = syn::parse_macro_input!(input as syn::Item);
 
-    let item_struct = if let syn::Item::Struct(m) = ty {
-        m
-    } else {
-        panic!("config attribute must be applied to a Struct");
+    expand_config(&args, ty)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_config(
+    args: &syn::AttributeArgs,
+    ty: syn::Item,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let mut file_path = None;
+    let mut custom_validate = false;
+    let mut section = false;
+    for arg in args {
+        if let Some(p) = get_config_file_path(arg) {
+            file_path = Some(p);
+        } else if is_flag_arg(arg, "validate") {
+            custom_validate = true;
+        } else if is_flag_arg(arg, "section") {
+            section = true;
+        } else {
+            return Err(syn::Error::new_spanned(
+                arg,
+                "unknown config argument, expected `path = \"...\"`, `validate` or `section`",
+            ));
+        }
+    }
+
+    let item_struct = match ty {
+        syn::Item::Struct(m) => m,
+        syn::Item::Enum(e) => {
+            return Err(syn::Error::new_spanned(
+                e.enum_token,
+                "config attribute must be applied to a Struct",
+            ))
+        }
+        other => {
+            return Err(syn::Error::new_spanned(
+                other,
+                "config attribute must be applied to a Struct",
+            ))
+        }
     };
 
     let name = &item_struct.ident;
@@ -29,17 +63,21 @@ pub fn config(args: TokenStream, input: TokenStream) -> TokenStream {
     {
         named
     } else {
-        panic!("config attribute must be applied to a Struct with named fields");
+        return Err(syn::Error::new_spanned(
+            &item_struct.fields,
+            "config attribute must be applied to a Struct with named fields",
+        ));
     };
 
+    check_field_attrs(fields)?;
+
     let config_fields = fields.iter().map(|f| {
         let name = &f.ident;
         let ty = &f.ty;
 
-        if name.as_ref().unwrap() == "threads" && is_matrix_field(f) {
-            panic!("threads can't be marked as matrix, it's matrix by definition");
-        }
-        if is_matrix_field(f) {
+        if is_nested_field(f) {
+            quote! {#name: <#ty as shumai::ConfigSection>::Matrix}
+        } else if is_matrix_field(f) {
             // // If the type is Option, return Option<Vec<ty>>; otherwise return Vec<ty>
             if let Some(t) = get_optional_inner_type(ty) {
                 quote! {#name: std::option::Option<std::vec::Vec<#t>>}
@@ -51,10 +89,67 @@ pub fn config(args: TokenStream, input: TokenStream) -> TokenStream {
         }
     });
 
-    let methods = gen_methods(fields, 0, name);
+    if section {
+        if file_path.is_some() {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "a config section is loaded as part of its parent config and can't have a `path`",
+            ));
+        }
+        let methods = gen_methods(fields, 0, name, Leaf::Section);
+        return Ok(quote! {
+            #[derive(Debug, shumai::__dep::serde::Deserialize)]
+            #[serde(deny_unknown_fields)]
+            pub struct #matrix_name {
+                #(#config_fields, )*
+            }
+
+            impl #matrix_name {
+                pub fn unfold(&self) -> std::vec::Vec<(#name, std::string::String)> {
+                    let mut configs: std::vec::Vec<(#name, std::string::String)> = std::vec::Vec::new();
+
+                    #methods
+
+                    configs
+                }
+            }
+
+            #[derive(Debug, Clone, shumai::ShumaiConfig, shumai::__dep::serde::Serialize, shumai::__dep::serde::Deserialize)]
+            #item_struct
+
+            impl shumai::ConfigSection for #name {
+                type Matrix = #matrix_name;
+
+                fn unfold(matrix: &Self::Matrix) -> std::vec::Vec<(Self, std::string::String)> {
+                    matrix.unfold()
+                }
+            }
+        });
+    }
+
+    let file_path =
+        file_path.ok_or_else(|| syn::Error::new(proc_macro2::Span::call_site(), USAGE))?;
+    check_required_fields(name, fields)?;
+
+    let methods = gen_methods(fields, 0, name, Leaf::Config);
     let dummy_struct_name = syn::Ident::new(&format!("{name}DummyStruct"), name.span());
+    let name_str = name.to_string();
+    let override_fields = fields.iter().map(|f| {
+        let f_name = f.ident.as_ref().unwrap().to_string();
+        let is_matrix = is_matrix_field(f);
+        quote! {(#f_name, #is_matrix)}
+    });
+    let validate_impl = if custom_validate {
+        quote! {}
+    } else {
+        quote! {
+            impl shumai::Validate for #name {}
+        }
+    };
+
     let expanded = quote! {
         #[derive(Debug, shumai::__dep::serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
         pub struct #matrix_name {
             #(#config_fields, )*
         }
@@ -79,34 +174,55 @@ pub fn config(args: TokenStream, input: TokenStream) -> TokenStream {
         }
 
         impl #name {
+            /// Loads the configs from the file in `#[config(path = "...")]`, or from `SHUMAI_CONFIG` if it is set.
+            pub fn load() -> std::result::Result<shumai::Loaded<#name>, shumai::ConfigError> {
+                Self::load_with_args(std::iter::empty())
+            }
+
+            /// Same as `load()`, but also applies `--set field=value` overrides found in `args`,
+            /// e.g. `load_with_args(std::env::args())`.
+            pub fn load_with_args<I: std::iter::IntoIterator<Item = std::string::String>>(args: I) -> std::result::Result<shumai::Loaded<#name>, shumai::ConfigError> {
+                let path = shumai::__private::config_path(#file_path);
+                Self::load_from_with_args(path, args)
+            }
+
+            pub fn load_from<P: std::convert::AsRef<std::path::Path>>(path: P) -> std::result::Result<shumai::Loaded<#name>, shumai::ConfigError> {
+                Self::load_from_with_args(path, std::iter::empty())
+            }
+
+            pub fn load_from_with_args<P: std::convert::AsRef<std::path::Path>, I: std::iter::IntoIterator<Item = std::string::String>>(path: P, args: I) -> std::result::Result<shumai::Loaded<#name>, shumai::ConfigError> {
+                let path = path.as_ref();
+                let contents = shumai::__private::read_config(path)?;
+                Self::parse_config(&contents, Some(path), args)
+            }
+
+            pub fn load_from_str(contents: &str) -> std::result::Result<shumai::Loaded<#name>, shumai::ConfigError> {
+                Self::parse_config(contents, None, std::iter::empty())
+            }
+
             #[allow(non_snake_case)]
-            pub fn load() -> std::option::Option<std::vec::Vec<#name>> {
-                let contents = std::fs::read_to_string(#file_path).expect(&format!("failed to read the benchmark config file at {}", #file_path));
-                let configs = shumai::__dep::toml::from_str::<#dummy_struct_name>(&contents).expect(&format!("failed to parse the benchmark config file at {}", #file_path));
+            fn parse_config<I: std::iter::IntoIterator<Item = std::string::String>>(contents: &str, path: std::option::Option<&std::path::Path>, args: I) -> std::result::Result<shumai::Loaded<#name>, shumai::ConfigError> {
+                let fields: &[(&str, bool)] = &[#(#override_fields),*];
+                let overrides = shumai::__private::collect_overrides(args, fields)?;
+                let configs = shumai::__private::parse_config::<#dummy_struct_name>(contents, path, #name_str, fields, &overrides)?;
 
-                let configs = configs.#name?;
+                let configs = configs.#name.ok_or_else(|| shumai::__private::missing_table(path, #name_str))?;
 
                 let mut expanded = std::vec::Vec::new();
                 for b in configs.iter() {
                     expanded.extend(b.unfold());
                 }
 
+                let expanded = shumai::__private::filter_configs(expanded)?;
+                shumai::__private::validate_configs(&expanded)?;
 
-                match std::env::var("SHUMAI_FILTER") {
-                    Ok(filter) => {
-                        let regex_filter =
-                            shumai::__dep::regex::Regex::new(filter.as_ref())
-                            .expect(&format!("Filter {} from env `SHUMAI_FILTER` is not a valid regex expression!", filter));
-                        let configs: std::vec::Vec<_> = expanded.into_iter().filter(|c| regex_filter.is_match(&c.name)).collect();
-                        Some(configs)
-                    },
-                    Err(_) => {
-                        Some(expanded)
-                    }
-                }
+                let record = shumai::LoadRecord::new(path, contents, overrides);
+                Ok(shumai::Loaded::new(expanded, record))
             }
         }
 
+        #validate_impl
+
         impl shumai::BenchConfig for #name {
             fn name(&self) -> &String {
                 &self.name
@@ -123,10 +239,93 @@ pub fn config(args: TokenStream, input: TokenStream) -> TokenStream {
     };
 
     // eprintln!("{}", expanded);
-    expanded.into()
+    Ok(expanded)
+}
+
+/// Every config must have `name: String`, `threads: Vec<usize>` and `time: usize`,
+/// and `threads` can't be a matrix field.
+fn check_required_fields(
+    struct_name: &syn::Ident,
+    fields: &syn::punctuated::Punctuated<syn::Field, syn::Token![,]>,
+) -> syn::Result<()> {
+    let required = [
+        ("name", "String", None),
+        ("threads", "Vec", Some("usize")),
+        ("time", "usize", None),
+    ];
+
+    let mut errors: Option<syn::Error> = None;
+    let mut push_error = |e: syn::Error| match errors.as_mut() {
+        Some(errors) => errors.combine(e),
+        None => errors = Some(e),
+    };
+
+    for (field_name, expected, expected_arg) in required {
+        let expected_ty = match expected_arg {
+            Some(arg) => format!("{expected}<{arg}>"),
+            None => expected.to_string(),
+        };
+        let field = fields
+            .iter()
+            .find(|f| f.ident.as_ref().is_some_and(|i| i == field_name));
+        match field {
+            Some(f) => {
+                let ty = &f.ty;
+                if !is_type(ty, expected, expected_arg) {
+                    push_error(syn::Error::new_spanned(
+                        ty,
+                        format!("`{field_name}` must be of type `{expected_ty}`"),
+                    ));
+                }
+            }
+            None => push_error(syn::Error::new_spanned(
+                struct_name,
+                format!("config is missing the required field `{field_name}: {expected_ty}`"),
+            )),
+        }
+    }
+
+    for f in fields {
+        if let Some(attr) = matrix_attr(f) {
+            if f.ident.as_ref().is_some_and(|i| i == "threads") {
+                push_error(syn::Error::new_spanned(
+                    attr,
+                    "threads can't be marked as matrix, it's matrix by definition",
+                ));
+            }
+        }
+    }
+
+    match errors {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Whether `ty` is the type `name`, or `name<arg>`, compared by the last path segment
+/// so that `String` and `std::string::String` are the same type.
+fn is_type(ty: &syn::Type, name: &str, arg: Option<&str>) -> bool {
+    let segment = match ty {
+        syn::Type::Path(syn::TypePath { qself: None, path }) => match path.segments.last() {
+            Some(s) => s,
+            None => return false,
+        },
+        _ => return false,
+    };
+    if segment.ident != name {
+        return false;
+    }
+    match (&segment.arguments, arg) {
+        (syn::PathArguments::None, None) => true,
+        (syn::PathArguments::AngleBracketed(args), Some(arg)) => {
+            args.args.len() == 1
+                && matches!(args.args.first(), Some(GenericArgument::Type(t)) if is_type(t, arg, None))
+        }
+        _ => false,
+    }
 }
 
-#[proc_macro_derive(ShumaiConfig, attributes(matrix))]
+#[proc_macro_derive(ShumaiConfig, attributes(matrix, nested))]
 pub fn derive_bench_config(_input: TokenStream) -> TokenStream {
     quote!().into()
 }
@@ -136,33 +335,50 @@ fn gen_matrix_name(name: &syn::Ident) -> syn::Ident {
     syn::Ident::new(&gen_name, name.span())
 }
 
+/// What the innermost loop of the matrix expansion builds.
+#[derive(Clone, Copy)]
+enum Leaf {
+    /// A top-level config, named after the struct, its `name` field and the matrix values.
+    Config,
+    /// A `#[config(section)]`, paired with the label of its matrix values for the parent's name.
+    Section,
+}
+
 fn gen_methods(
     fields: &syn::punctuated::Punctuated<syn::Field, syn::Token![,]>,
     current: usize,
     origin_name: &syn::Ident,
+    leaf: Leaf,
 ) -> proc_macro2::TokenStream {
     if current == fields.len() {
-        let name_prefix = origin_name.to_string().to_ascii_lowercase();
-        let mut name_gen = quote! {
-            let mut name_lit = format!("{}-{}", #name_prefix, name.clone());
+        let mut label_gen = quote! {
+            let mut labels: std::vec::Vec<std::string::String> = std::vec::Vec::new();
         };
         for f in fields {
             let f_name = &f.ident;
-            if is_matrix_field(f) {
+            if is_nested_field(f) {
+                let label = gen_label_name(f);
+                label_gen = quote! {
+                    #label_gen
+                    if !#label.is_empty() {
+                        labels.push(#label.clone());
+                    }
+                }
+            } else if is_matrix_field(f) {
                 if get_optional_inner_type(&f.ty).is_some() {
-                    name_gen = quote! {
-                        #name_gen
+                    label_gen = quote! {
+                        #label_gen
                         if let Some(t_v) = &self.#f_name {
                             if t_v.len() > 1 {
-                                name_lit = format!("{}-{:?}", name_lit, #f_name);
+                                labels.push(format!("{:?}", #f_name));
                             }
                         }
                     }
                 } else {
-                    name_gen = quote! {
-                        #name_gen
+                    label_gen = quote! {
+                        #label_gen
                         if self.#f_name.len() > 1 {
-                            name_lit = format!("{}-{:?}", name_lit, #f_name);
+                            labels.push(format!("{:?}", #f_name));
                         }
                     }
                 }
@@ -171,7 +387,7 @@ fn gen_methods(
         let assign_fields = fields.iter().map(|f| {
             let name = &f.ident;
             // We skip the `name` field because it's handled separately
-            if name.as_ref().unwrap() == "name" {
+            if matches!(leaf, Leaf::Config) && name.as_ref().unwrap() == "name" {
                 quote! {}
             } else {
                 quote! {
@@ -180,21 +396,44 @@ fn gen_methods(
             }
         });
 
-        return quote! {
-            #name_gen
-            configs.push(#origin_name {
-                name: name_lit,
-                #(#assign_fields)*
-            });
+        return match leaf {
+            Leaf::Config => {
+                let name_prefix = origin_name.to_string().to_ascii_lowercase();
+                quote! {
+                    #label_gen
+                    let mut name_lit = format!("{}-{}", #name_prefix, name.clone());
+                    for l in labels.iter() {
+                        name_lit = format!("{}-{}", name_lit, l);
+                    }
+                    configs.push(#origin_name {
+                        name: name_lit,
+                        #(#assign_fields)*
+                    });
+                }
+            }
+            Leaf::Section => quote! {
+                #label_gen
+                configs.push((#origin_name {
+                    #(#assign_fields)*
+                }, labels.join("-")));
+            },
         };
     }
 
-    let inner = gen_methods(fields, current + 1, origin_name);
+    let inner = gen_methods(fields, current + 1, origin_name, leaf);
 
     let current = &fields[current];
     let name = &current.ident;
 
-    if is_matrix_field(current) {
+    if is_nested_field(current) {
+        let ty = &current.ty;
+        let label = gen_label_name(current);
+        quote! {
+            for (#name, #label) in <#ty as shumai::ConfigSection>::unfold(&self.#name) {
+                #inner
+            }
+        }
+    } else if is_matrix_field(current) {
         if get_optional_inner_type(&current.ty).is_some() {
             quote! {
                 if let Some(#name) = &self.#name {
@@ -223,13 +462,41 @@ fn gen_methods(
     }
 }
 
+fn gen_label_name(f: &syn::Field) -> syn::Ident {
+    format_ident!("__shumai_label_{}", f.ident.as_ref().unwrap())
+}
+
 fn is_matrix_field(f: &syn::Field) -> bool {
-    for attr in &f.attrs {
-        if attr.path.segments.len() == 1 && attr.path.segments[0].ident == "matrix" {
-            return true;
+    matrix_attr(f).is_some()
+}
+
+fn matrix_attr(f: &syn::Field) -> Option<&syn::Attribute> {
+    field_attr(f, "matrix")
+}
+
+fn is_nested_field(f: &syn::Field) -> bool {
+    field_attr(f, "nested").is_some()
+}
+
+fn field_attr<'a>(f: &'a syn::Field, name: &str) -> Option<&'a syn::Attribute> {
+    f.attrs
+        .iter()
+        .find(|attr| attr.path.segments.len() == 1 && attr.path.segments[0].ident == name)
+}
+
+/// A field is either a plain value, a `#[matrix]` of values, or a `#[nested]` config section.
+fn check_field_attrs(
+    fields: &syn::punctuated::Punctuated<syn::Field, syn::Token![,]>,
+) -> syn::Result<()> {
+    for f in fields {
+        if let (Some(_), Some(nested)) = (matrix_attr(f), field_attr(f, "nested")) {
+            return Err(syn::Error::new_spanned(
+                nested,
+                "a field can't be both #[matrix] and #[nested], mark the fields inside the section as #[matrix] instead",
+            ));
         }
     }
-    false
+    Ok(())
 }
 
 fn get_optional_inner_type(ty: &syn::Type) -> Option<&GenericArgument> {
@@ -247,10 +514,10 @@ fn get_optional_inner_type(ty: &syn::Type) -> Option<&GenericArgument> {
                         args,
                         ..
                     }) => {
-                        let ty = args.first().unwrap();
-                        return Some(ty);
+                        return args.first();
                     }
-                    _ => panic!("Option must be used with angle bracketed generic arguments"),
+                    // not a usable Option, leave it to rustc to report
+                    _ => return None,
                 }
             }
         }
@@ -258,6 +525,10 @@ fn get_optional_inner_type(ty: &syn::Type) -> Option<&GenericArgument> {
     None
 }
 
+fn is_flag_arg(meta: &syn::NestedMeta, flag: &str) -> bool {
+    matches!(meta, syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident(flag))
+}
+
 fn get_config_file_path(meta: &syn::NestedMeta) -> Option<syn::LitStr> {
     let meta = if let syn::NestedMeta::Meta(m) = meta {
         m
@@ -0,0 +1,88 @@
+//! A global allocator that counts allocations, for the `alloc` measurement.
+//!
+//! Install it in the benchmark binary:
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: shumai::alloc::CountingAllocator = shumai::alloc::CountingAllocator::system();
+//! ```
+#![allow(unsafe_code)]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+pub(crate) static INSTALLED: AtomicBool = AtomicBool::new(false);
+pub(crate) static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static REALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static BYTES_ALLOCATED: AtomicU64 = AtomicU64::new(0);
+pub(crate) static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+pub(crate) static PEAK_LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Wraps another allocator, `System` by default, and counts what goes through it.
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+impl CountingAllocator<System> {
+    pub const fn system() -> Self {
+        Self { inner: System }
+    }
+}
+
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+fn on_alloc(size: usize) {
+    INSTALLED.store(true, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_ALLOCATED.fetch_add(size as u64, Ordering::Relaxed);
+    let live = LIVE_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_LIVE_BYTES.fetch_max(live, Ordering::Relaxed);
+}
+
+fn on_dealloc(size: usize) {
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        on_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            REALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            if new_size > layout.size() {
+                let grown = new_size - layout.size();
+                BYTES_ALLOCATED.fetch_add(grown as u64, Ordering::Relaxed);
+                let live = LIVE_BYTES.fetch_add(grown, Ordering::Relaxed) + grown;
+                PEAK_LIVE_BYTES.fetch_max(live, Ordering::Relaxed);
+            } else {
+                LIVE_BYTES.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
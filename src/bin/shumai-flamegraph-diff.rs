@@ -0,0 +1,60 @@
+//! Differential flamegraph between two benchmark result files of the same config.
+//!
+//! ```text
+//! shumai-flamegraph-diff <baseline.json> <candidate.json> --threads <n> [-o <output.svg>]
+//! ```
+
+use std::path::PathBuf;
+
+const USAGE: &str =
+    "usage: shumai-flamegraph-diff <baseline.json> <candidate.json> --threads <n> [-o <output.svg>]";
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
+
+fn main() {
+    let mut files = Vec::new();
+    let mut threads = None;
+    let mut output = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--threads" | "-t" => {
+                let t = args.next().unwrap_or_else(|| exit_with(USAGE));
+                threads = Some(
+                    t.parse::<usize>()
+                        .unwrap_or_else(|_| exit_with("--threads must be a number")),
+                );
+            }
+            "--output" | "-o" => {
+                output = Some(PathBuf::from(
+                    args.next().unwrap_or_else(|| exit_with(USAGE)),
+                ))
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+
+    let (baseline, candidate) = match files.as_slice() {
+        [baseline, candidate] => (baseline, candidate),
+        _ => exit_with(USAGE),
+    };
+    let threads = threads.unwrap_or_else(|| exit_with(USAGE));
+    let output =
+        output.unwrap_or_else(|| candidate.with_extension(format!("diff-{}t.svg", threads)));
+
+    match shumai::flamegraph::diff(baseline, candidate, threads, &output) {
+        Ok(()) => println!(
+            "Differential flamegraph saved to file: {}",
+            output.display()
+        ),
+        Err(e) => exit_with(&e.to_string()),
+    }
+}
@@ -1,4 +1,6 @@
-#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "alloc"), forbid(unsafe_code))]
+// the counting allocator has to implement `GlobalAlloc`, everything else stays safe
+#![cfg_attr(feature = "alloc", deny(unsafe_code))]
 
 use std::{
     fmt::Display,
@@ -9,14 +11,24 @@ use std::{
 
 use serde::Serialize;
 
+#[cfg(feature = "alloc")]
+pub mod alloc;
 mod env;
+mod loader;
 mod metrics;
 mod result;
 mod runner;
+pub use loader::{ConfigError, ConfigOverride, ConfigSource, LoadRecord, Loaded, OverrideSource};
 pub use result::ShumaiResult;
-pub use runner::run;
+pub use runner::{run, run_loaded};
 pub use shumai_config_impl::{config, ShumaiConfig};
 
+/// Compare flamegraphs between benchmark runs.
+#[cfg(feature = "flamegraph")]
+pub mod flamegraph {
+    pub use crate::metrics::flamegraph::{diff, DiffError};
+}
+
 pub mod __dep {
     pub use colored;
     pub use regex;
@@ -25,6 +37,14 @@ pub mod __dep {
     pub use toml;
 }
 
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::loader::{
+        collect_overrides, config_path, filter_configs, missing_table, parse_config, read_config,
+        validate_configs,
+    };
+}
+
 /// The context send to MultiBench::run()
 pub struct Context<'a, C: BenchConfig> {
     running: &'a AtomicBool,
@@ -91,6 +111,28 @@ pub trait BenchConfig: Clone + Serialize + Send + Sync {
     fn bench_sec(&self) -> usize;
 }
 
+/// A nested section of a config, implemented by `#[config(section)]`.
+///
+/// A parent config marks the field as `#[nested]`, the `#[matrix]` fields inside the section
+/// are expanded together with the parent's own matrix fields.
+pub trait ConfigSection: Sized {
+    type Matrix: serde::de::DeserializeOwned + std::fmt::Debug;
+
+    /// Expands the section's matrix, each config is paired with a label of its matrix values
+    /// which is appended to the parent config's name.
+    fn unfold(matrix: &Self::Matrix) -> Vec<(Self, String)>;
+}
+
+/// Semantic checks on a config, run on every config returned by `load()` before benchmarking starts.
+///
+/// To provide your own checks, annotate the config with `#[config(path = "...", validate)]`
+/// and implement this trait; otherwise the config macro implements it with no checks.
+pub trait Validate {
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
 /// The call chain of a MultiThreadBench:
 /// load() -> run() [thread t1] -> run() [thread t2] -> ... -> cleanup()
 pub trait ShumaiBench: Send + Sync {
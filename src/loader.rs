@@ -0,0 +1,417 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::{BenchConfig, Validate};
+
+const ENV_PREFIX: &str = "SHUMAI_SET_";
+const CONFIG_PATH_ENV: &str = "SHUMAI_CONFIG";
+
+/// Errors from loading a benchmark config.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The config file is not valid toml or doesn't match the config struct,
+    /// `line` and `column` are 1-based and only known when the error can be located in the file.
+    Parse {
+        origin: String,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+    MissingTable {
+        origin: String,
+        table: String,
+    },
+    Override(String),
+    Filter {
+        filter: String,
+        message: String,
+    },
+    Validation {
+        name: String,
+        message: String,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(
+                f,
+                "failed to read the benchmark config file at {}: {}",
+                path.display(),
+                source
+            ),
+            ConfigError::Parse {
+                origin,
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => write!(
+                f,
+                "failed to parse the benchmark config file at {}:{}:{}: {}",
+                origin, line, column, message
+            ),
+            ConfigError::Parse {
+                origin, message, ..
+            } => write!(
+                f,
+                "failed to parse the benchmark config file at {}: {}",
+                origin, message
+            ),
+            ConfigError::MissingTable { origin, table } => {
+                write!(f, "no [[{}]] table found in {}", table, origin)
+            }
+            ConfigError::Override(message) => write!(f, "invalid config override: {}", message),
+            ConfigError::Filter { filter, message } => write!(
+                f,
+                "Filter {} from env `SHUMAI_FILTER` is not a valid regex expression: {}",
+                filter, message
+            ),
+            ConfigError::Validation { name, message } => {
+                write!(f, "config {} failed validation: {}", name, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Where a config was loaded from, `path` is `None` if it was loaded from a string.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigSource {
+    pub path: Option<PathBuf>,
+    pub sha256: String,
+}
+
+impl ConfigSource {
+    pub(crate) fn new(path: Option<&Path>, contents: &str) -> Self {
+        let path = path.map(|p| std::fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf()));
+        let sha256 = Sha256::digest(contents.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Self { path, sha256 }
+    }
+}
+
+/// A config field overridden at load time, either from the command line (`--set field=value`)
+/// or from an environment variable (`SHUMAI_SET_<FIELD>=value`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigOverride {
+    pub field: String,
+    pub value: String,
+    pub source: OverrideSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverrideSource {
+    Env,
+    Cli,
+}
+
+/// What a `load()` recorded about its configs, written to the results by [`crate::run_loaded`].
+#[derive(Debug, Clone, Default)]
+pub struct LoadRecord {
+    source: Option<ConfigSource>,
+    overrides: Vec<ConfigOverride>,
+}
+
+impl LoadRecord {
+    /// The record of a successful load, shared by every config it returned.
+    #[doc(hidden)]
+    pub fn new(path: Option<&Path>, contents: &str, overrides: Vec<ConfigOverride>) -> Self {
+        Self {
+            source: Some(ConfigSource::new(path, contents)),
+            overrides,
+        }
+    }
+
+    /// The file (or string) the configs were loaded from.
+    pub fn source(&self) -> Option<&ConfigSource> {
+        self.source.as_ref()
+    }
+
+    /// The overrides applied to the configs at load time.
+    pub fn overrides(&self) -> &[ConfigOverride] {
+        &self.overrides
+    }
+}
+
+/// The configs returned by `load()`, and the record of that load.
+///
+/// Derefs to the slice of configs, e.g. `configs.iter()` or `configs[0]`.
+#[derive(Debug, Clone)]
+pub struct Loaded<T> {
+    configs: Vec<T>,
+    record: LoadRecord,
+}
+
+impl<T> Loaded<T> {
+    #[doc(hidden)]
+    pub fn new(configs: Vec<T>, record: LoadRecord) -> Self {
+        Self { configs, record }
+    }
+
+    pub fn record(&self) -> &LoadRecord {
+        &self.record
+    }
+
+    pub fn source(&self) -> Option<&ConfigSource> {
+        self.record.source()
+    }
+
+    pub fn overrides(&self) -> &[ConfigOverride] {
+        self.record.overrides()
+    }
+
+    pub fn into_configs(self) -> Vec<T> {
+        self.configs
+    }
+}
+
+impl<T> std::ops::Deref for Loaded<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.configs
+    }
+}
+
+impl<T> IntoIterator for Loaded<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.configs.into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Loaded<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.configs.iter()
+    }
+}
+
+/// The config file to load, `SHUMAI_CONFIG` takes precedence over the path in `#[config(path = "...")]`.
+pub fn config_path(default: &str) -> PathBuf {
+    match std::env::var(CONFIG_PATH_ENV) {
+        Ok(path) => {
+            eprintln!(
+                "Using environment variable {} to load config from {}",
+                CONFIG_PATH_ENV, path
+            );
+            PathBuf::from(path)
+        }
+        Err(_) => PathBuf::from(default),
+    }
+}
+
+pub fn read_config(path: &Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn origin(path: Option<&Path>) -> String {
+    path.map(|p| p.display().to_string())
+        .unwrap_or_else(|| "<string>".to_string())
+}
+
+/// Collects overrides from `SHUMAI_SET_<FIELD>` environment variables and `--set field=value` arguments.
+/// Command line overrides come last so they take precedence over the environment.
+///
+/// The environment is shared by every config struct of the binary, so variables for fields
+/// that are not in `fields` are ignored, while an unknown field on the command line is an error.
+pub fn collect_overrides<I: IntoIterator<Item = String>>(
+    args: I,
+    fields: &[(&str, bool)],
+) -> Result<Vec<ConfigOverride>, ConfigError> {
+    let mut env_overrides: Vec<_> = std::env::vars()
+        .filter_map(|(k, v)| {
+            let field = k.strip_prefix(ENV_PREFIX)?;
+            Some(ConfigOverride {
+                field: field.to_ascii_lowercase(),
+                value: v,
+                source: OverrideSource::Env,
+            })
+        })
+        .filter(|o| fields.iter().any(|(f, _)| f.eq_ignore_ascii_case(&o.field)))
+        .collect();
+    env_overrides.sort_by(|a, b| a.field.cmp(&b.field));
+
+    let mut overrides = env_overrides;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let assignment = if arg == "--set" {
+            args.next().ok_or_else(|| {
+                ConfigError::Override(
+                    "`--set` expects an argument of the form `field=value`".to_string(),
+                )
+            })?
+        } else if let Some(a) = arg.strip_prefix("--set=") {
+            a.to_string()
+        } else {
+            continue;
+        };
+
+        let (field, value) = assignment.split_once('=').ok_or_else(|| {
+            ConfigError::Override(format!("`{}` is not of the form `field=value`", assignment))
+        })?;
+        overrides.push(ConfigOverride {
+            field: field.trim().to_string(),
+            value: value.trim().to_string(),
+            source: OverrideSource::Cli,
+        });
+    }
+    Ok(overrides)
+}
+
+/// Applies the overrides to every `[[config_name]]` entry of the parsed benchmark file.
+///
+/// `fields` lists the struct fields and whether they are `#[matrix]` fields, a scalar value
+/// given to a matrix field is treated as a single element matrix.
+fn apply_overrides(
+    table: &mut toml::Table,
+    config_name: &str,
+    fields: &[(&str, bool)],
+    overrides: &[ConfigOverride],
+) -> Result<(), ConfigError> {
+    let entries = match table.get_mut(config_name) {
+        Some(toml::Value::Array(entries)) => entries,
+        _ => return Ok(()),
+    };
+
+    for o in overrides {
+        let (field, is_matrix) = fields
+            .iter()
+            .find(|(f, _)| f.eq_ignore_ascii_case(&o.field))
+            .ok_or_else(|| {
+                ConfigError::Override(format!(
+                    "Unknown field `{}` for {}, available fields: {}",
+                    o.field,
+                    config_name,
+                    fields
+                        .iter()
+                        .map(|(f, _)| *f)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            })?;
+
+        let value = match parse_value(&o.value) {
+            toml::Value::Array(a) if *is_matrix => toml::Value::Array(a),
+            v if *is_matrix => toml::Value::Array(vec![v]),
+            v => v,
+        };
+
+        for entry in entries.iter_mut() {
+            if let toml::Value::Table(entry) = entry {
+                entry.insert(field.to_string(), value.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses an override as a TOML value, falling back to a plain string so that
+/// `--set name=foo` works without quoting.
+fn parse_value(s: &str) -> toml::Value {
+    toml::Value::deserialize(toml::de::ValueDeserializer::new(s))
+        .unwrap_or_else(|_| toml::Value::String(s.to_string()))
+}
+
+fn parse_error(path: Option<&Path>, contents: &str, e: toml::de::Error) -> ConfigError {
+    let (line, column) = match e.span() {
+        Some(span) => {
+            let before = &contents[..span.start.min(contents.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            (Some(line), Some(column))
+        }
+        None => (None, None),
+    };
+    ConfigError::Parse {
+        origin: origin(path),
+        line,
+        column,
+        message: e.message().to_string(),
+    }
+}
+
+/// Parses the benchmark file and applies the overrides.
+///
+/// Without overrides the file is deserialized directly so that errors point to a line and column,
+/// otherwise the overrides are applied to the parsed toml before it is deserialized.
+pub fn parse_config<D: DeserializeOwned>(
+    contents: &str,
+    path: Option<&Path>,
+    config_name: &str,
+    fields: &[(&str, bool)],
+    overrides: &[ConfigOverride],
+) -> Result<D, ConfigError> {
+    if overrides.is_empty() {
+        return toml::from_str::<D>(contents).map_err(|e| parse_error(path, contents, e));
+    }
+
+    let mut table =
+        toml::from_str::<toml::Table>(contents).map_err(|e| parse_error(path, contents, e))?;
+    apply_overrides(&mut table, config_name, fields, overrides)?;
+    toml::Value::Table(table)
+        .try_into::<D>()
+        .map_err(|e| parse_error(path, contents, e))
+}
+
+pub fn missing_table(path: Option<&Path>, config_name: &str) -> ConfigError {
+    ConfigError::MissingTable {
+        origin: origin(path),
+        table: config_name.to_string(),
+    }
+}
+
+/// Keeps the configs whose name matches the regex in `SHUMAI_FILTER`, if it is set.
+pub fn filter_configs<C: BenchConfig>(configs: Vec<C>) -> Result<Vec<C>, ConfigError> {
+    match std::env::var("SHUMAI_FILTER") {
+        Ok(filter) => {
+            let regex_filter =
+                regex::Regex::new(filter.as_ref()).map_err(|e| ConfigError::Filter {
+                    message: e.to_string(),
+                    filter,
+                })?;
+            Ok(configs
+                .into_iter()
+                .filter(|c| regex_filter.is_match(c.name()))
+                .collect())
+        }
+        Err(_) => Ok(configs),
+    }
+}
+
+pub fn validate_configs<C: BenchConfig + Validate>(configs: &[C]) -> Result<(), ConfigError> {
+    for c in configs {
+        c.validate().map_err(|message| ConfigError::Validation {
+            name: c.name().clone(),
+            message,
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use super::{Measure, MeasureContext, Measurement};
+use crate::alloc::{
+    ALLOCATIONS, BYTES_ALLOCATED, DEALLOCATIONS, INSTALLED, LIVE_BYTES, PEAK_LIVE_BYTES,
+    REALLOCATIONS,
+};
+
+/// Heap activity of the whole process over the run window, from [`crate::alloc::CountingAllocator`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AllocStats {
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub reallocations: u64,
+    /// Bytes requested by allocations, plus the growth of reallocations.
+    pub bytes_allocated: u64,
+    pub live_bytes_start: u64,
+    pub peak_live_bytes: u64,
+    /// `None` if the benchmark reported no operations.
+    pub allocations_per_op: Option<f64>,
+    pub bytes_per_op: Option<f64>,
+}
+
+#[derive(Default)]
+struct Counters {
+    allocations: u64,
+    deallocations: u64,
+    reallocations: u64,
+    bytes_allocated: u64,
+}
+
+impl Counters {
+    fn read() -> Counters {
+        Counters {
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+            reallocations: REALLOCATIONS.load(Ordering::Relaxed),
+            bytes_allocated: BYTES_ALLOCATED.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct AllocMeasurement {
+    start: Counters,
+    live_bytes_start: usize,
+    stats: AllocStats,
+}
+
+impl AllocMeasurement {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Counters::default(),
+            live_bytes_start: 0,
+            stats: AllocStats::default(),
+        }
+    }
+}
+
+impl Measurement for AllocMeasurement {
+    fn start(&mut self) {
+        self.live_bytes_start = LIVE_BYTES.load(Ordering::Relaxed);
+        PEAK_LIVE_BYTES.store(self.live_bytes_start, Ordering::Relaxed);
+        self.start = Counters::read();
+    }
+
+    fn stop(&mut self) {
+        let end = Counters::read();
+        self.stats = AllocStats {
+            allocations: end.allocations - self.start.allocations,
+            deallocations: end.deallocations - self.start.deallocations,
+            reallocations: end.reallocations - self.start.reallocations,
+            bytes_allocated: end.bytes_allocated - self.start.bytes_allocated,
+            live_bytes_start: self.live_bytes_start as u64,
+            peak_live_bytes: PEAK_LIVE_BYTES.load(Ordering::Relaxed) as u64,
+            allocations_per_op: None,
+            bytes_per_op: None,
+        };
+    }
+
+    fn result(&mut self, ctx: &MeasureContext) -> Measure {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            return Measure::new(
+                "alloc",
+                serde_json::Value::Null,
+                Some("shumai::alloc::CountingAllocator is not the #[global_allocator]".to_string()),
+            );
+        }
+
+        let mut stats = self.stats.clone();
+        if ctx.ops > 0 {
+            stats.allocations_per_op = Some(stats.allocations as f64 / ctx.ops as f64);
+            stats.bytes_per_op = Some(stats.bytes_allocated as f64 / ctx.ops as f64);
+        }
+
+        Measure::new("alloc", serde_json::to_value(stats).unwrap(), None)
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use super::{Measure, MeasureContext, Measurement};
+
+/// The cgroup directory to account, the cgroup v2 group of the benchmark process by default.
+const CGROUP_PATH_ENV: &str = "SHUMAI_CGROUP_PATH";
+
+/// `cpu.stat` of the cgroup, as a delta over the run window.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CgroupCpu {
+    pub usage_usec: u64,
+    pub user_usec: u64,
+    pub system_usec: u64,
+    /// Bandwidth control periods and the ones the cgroup was throttled in, 0 without a `cpu.max` limit.
+    pub nr_periods: u64,
+    pub nr_throttled: u64,
+    pub throttled_usec: u64,
+}
+
+/// Memory of the cgroup in bytes.
+#[derive(Debug, Clone, Serialize)]
+pub struct CgroupMemory {
+    pub current_start: u64,
+    pub current_end: u64,
+    /// Peak usage during the run window if `peak_reset`, otherwise since the cgroup was created.
+    pub peak: Option<u64>,
+    /// Whether `memory.peak` could be reset when the run started, which needs Linux 6.12.
+    pub peak_reset: bool,
+}
+
+/// `io.stat` of a device, as a delta over the run window.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CgroupIo {
+    /// `major:minor` of the block device.
+    pub device: String,
+    pub rbytes: u64,
+    pub wbytes: u64,
+    pub rios: u64,
+    pub wios: u64,
+    pub dbytes: u64,
+    pub dios: u64,
+}
+
+/// Stall time of a PSI file over the run window.
+#[derive(Debug, Clone, Serialize)]
+pub struct Pressure {
+    /// Time at least one task of the cgroup was stalled on the resource.
+    pub some_ms: f64,
+    /// Time all non-idle tasks were stalled at once, `None` for `cpu.pressure` of the root cgroup.
+    pub full_ms: Option<f64>,
+    /// `some_ms` as a fraction of the run window.
+    pub some_fraction: f64,
+    pub full_fraction: Option<f64>,
+}
+
+/// Every field is `None` if the cgroup does not have the file, e.g. the controller is not enabled.
+#[derive(Debug, Clone, Serialize)]
+pub struct CgroupUsage {
+    pub path: String,
+    pub cpu: Option<CgroupCpu>,
+    pub memory: Option<CgroupMemory>,
+    pub io: Option<Vec<CgroupIo>>,
+    pub cpu_pressure: Option<Pressure>,
+    pub memory_pressure: Option<Pressure>,
+    pub io_pressure: Option<Pressure>,
+}
+
+/// The cgroup v2 directory of this process, from `/proc/self/cgroup` and the `cgroup2` mount.
+fn own_cgroup() -> Result<PathBuf, String> {
+    let cgroup = std::fs::read_to_string("/proc/self/cgroup")
+        .map_err(|e| format!("unable to read /proc/self/cgroup: {}", e))?;
+    // the unified hierarchy is the `0::<path>` line, also present on hybrid setups
+    let path = cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .ok_or("the process is not in a cgroup v2 group")?;
+
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|e| format!("unable to read /proc/self/mountinfo: {}", e))?;
+    let mount = mountinfo
+        .lines()
+        .find(|l| {
+            l.split_once(" - ")
+                .is_some_and(|(_, fs)| fs.starts_with("cgroup2 "))
+        })
+        .and_then(|l| l.split_whitespace().nth(4))
+        .ok_or("cgroup v2 is not mounted")?;
+
+    Ok(Path::new(mount).join(path.trim_start_matches('/')))
+}
+
+/// Reads a flat keyed file like `cpu.stat`, `None` if the file does not exist.
+fn read_keyed(path: &Path) -> Result<Option<BTreeMap<String, u64>>, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("unable to read {}: {}", path.display(), e)),
+    };
+    content
+        .lines()
+        .filter_map(|l| l.split_once(' '))
+        .map(|(key, value)| {
+            value
+                .trim()
+                .parse::<u64>()
+                .map(|v| (key.to_string(), v))
+                .map_err(|e| format!("invalid `{} {}` in {}: {}", key, value, path.display(), e))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn read_single(path: &Path) -> Result<Option<u64>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(c) => c
+            .trim()
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|e| format!("invalid {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("unable to read {}: {}", path.display(), e)),
+    }
+}
+
+/// `io.stat` lines look like `8:0 rbytes=1 wbytes=2 rios=3 wios=4 dbytes=0 dios=0`.
+fn read_io_stat(path: &Path) -> Result<Option<BTreeMap<String, CgroupIo>>, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("unable to read {}: {}", path.display(), e)),
+    };
+    let mut devices = BTreeMap::new();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let device = match fields.next() {
+            Some(d) => d.to_string(),
+            None => continue,
+        };
+        let mut io = CgroupIo {
+            device: device.clone(),
+            ..Default::default()
+        };
+        for field in fields {
+            let (key, value) = field.split_once('=').unwrap_or_default();
+            let counter = match key {
+                "rbytes" => &mut io.rbytes,
+                "wbytes" => &mut io.wbytes,
+                "rios" => &mut io.rios,
+                "wios" => &mut io.wios,
+                "dbytes" => &mut io.dbytes,
+                "dios" => &mut io.dios,
+                _ => continue,
+            };
+            *counter = value
+                .parse()
+                .map_err(|e| format!("invalid `{}` in {}: {}", field, path.display(), e))?;
+        }
+        devices.insert(device, io);
+    }
+    Ok(Some(devices))
+}
+
+/// The `total=` stall times in microseconds of the `some` and `full` lines of a PSI file.
+fn read_pressure(path: &Path) -> Result<Option<(u64, Option<u64>)>, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        // PSI is disabled unless the kernel has `CONFIG_PSI` and was not booted with `psi=0`
+        Err(e)
+            if e.kind() == std::io::ErrorKind::NotFound
+                || e.raw_os_error() == Some(95 /* EOPNOTSUPP */) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(format!("unable to read {}: {}", path.display(), e)),
+    };
+    let total = |kind: &str| -> Result<Option<u64>, String> {
+        content
+            .lines()
+            .find(|l| l.starts_with(kind))
+            .and_then(|l| l.split_whitespace().find_map(|f| f.strip_prefix("total=")))
+            .map(|v| {
+                v.parse::<u64>()
+                    .map_err(|e| format!("invalid {}: {}", path.display(), e))
+            })
+            .transpose()
+    };
+    match total("some ")? {
+        Some(some) => Ok(Some((some, total("full ")?))),
+        None => Err(format!("no `some` line in {}", path.display())),
+    }
+}
+
+/// The counters of the cgroup files, `None` for the missing ones.
+struct Snapshot {
+    time: Instant,
+    cpu: Option<BTreeMap<String, u64>>,
+    memory_current: Option<u64>,
+    io: Option<BTreeMap<String, CgroupIo>>,
+    cpu_pressure: Option<(u64, Option<u64>)>,
+    memory_pressure: Option<(u64, Option<u64>)>,
+    io_pressure: Option<(u64, Option<u64>)>,
+}
+
+impl Snapshot {
+    fn read(dir: &Path) -> Result<Snapshot, String> {
+        Ok(Snapshot {
+            time: Instant::now(),
+            cpu: read_keyed(&dir.join("cpu.stat"))?,
+            memory_current: read_single(&dir.join("memory.current"))?,
+            io: read_io_stat(&dir.join("io.stat"))?,
+            cpu_pressure: read_pressure(&dir.join("cpu.pressure"))?,
+            memory_pressure: read_pressure(&dir.join("memory.pressure"))?,
+            io_pressure: read_pressure(&dir.join("io.pressure"))?,
+        })
+    }
+}
+
+fn cpu_delta(start: &BTreeMap<String, u64>, end: &BTreeMap<String, u64>) -> CgroupCpu {
+    let delta = |key: &str| {
+        end.get(key)
+            .copied()
+            .unwrap_or(0)
+            .saturating_sub(start.get(key).copied().unwrap_or(0))
+    };
+    CgroupCpu {
+        usage_usec: delta("usage_usec"),
+        user_usec: delta("user_usec"),
+        system_usec: delta("system_usec"),
+        nr_periods: delta("nr_periods"),
+        nr_throttled: delta("nr_throttled"),
+        throttled_usec: delta("throttled_usec"),
+    }
+}
+
+fn io_delta(start: &BTreeMap<String, CgroupIo>, end: &BTreeMap<String, CgroupIo>) -> Vec<CgroupIo> {
+    end.values()
+        .map(|e| {
+            let s = start.get(&e.device).cloned().unwrap_or_default();
+            CgroupIo {
+                device: e.device.clone(),
+                rbytes: e.rbytes.saturating_sub(s.rbytes),
+                wbytes: e.wbytes.saturating_sub(s.wbytes),
+                rios: e.rios.saturating_sub(s.rios),
+                wios: e.wios.saturating_sub(s.wios),
+                dbytes: e.dbytes.saturating_sub(s.dbytes),
+                dios: e.dios.saturating_sub(s.dios),
+            }
+        })
+        .collect()
+}
+
+fn pressure_delta(start: (u64, Option<u64>), end: (u64, Option<u64>), window_us: f64) -> Pressure {
+    let some_us = end.0.saturating_sub(start.0) as f64;
+    let full_us = match (start.1, end.1) {
+        (Some(s), Some(e)) => Some(e.saturating_sub(s) as f64),
+        _ => None,
+    };
+    let fraction = |us: f64| if window_us > 0.0 { us / window_us } else { 0.0 };
+    Pressure {
+        some_ms: some_us / 1000.0,
+        full_ms: full_us.map(|us| us / 1000.0),
+        some_fraction: fraction(some_us),
+        full_fraction: full_us.map(fraction),
+    }
+}
+
+/// Opens `memory.peak` and resets it, the reset only applies to reads through the same file.
+fn reset_peak(dir: &Path) -> Option<std::fs::File> {
+    let mut file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(dir.join("memory.peak"))
+        .ok()?;
+    file.write_all(b"0").ok()?;
+    Some(file)
+}
+
+fn read_peak(file: Option<&mut std::fs::File>, dir: &Path) -> Result<Option<u64>, String> {
+    match file {
+        Some(file) => {
+            let mut content = String::new();
+            file.rewind()
+                .and_then(|_| file.read_to_string(&mut content))
+                .map_err(|e| format!("unable to read memory.peak: {}", e))?;
+            content
+                .trim()
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|e| format!("invalid memory.peak: {}", e))
+        }
+        None => read_single(&dir.join("memory.peak")),
+    }
+}
+
+pub(crate) struct CgroupMeasurement {
+    dir: Result<PathBuf, String>,
+    start: Result<(Snapshot, Option<std::fs::File>), String>,
+    result: Result<CgroupUsage, String>,
+}
+
+impl CgroupMeasurement {
+    pub(crate) fn new() -> Self {
+        Self {
+            dir: match std::env::var(CGROUP_PATH_ENV) {
+                Ok(path) => Ok(path.into()),
+                Err(_) => own_cgroup(),
+            },
+            start: Err("the measurement was not started".to_string()),
+            result: Err("the measurement was not started".to_string()),
+        }
+    }
+
+    fn usage(
+        dir: &Path,
+        start: Snapshot,
+        mut peak_file: Option<std::fs::File>,
+    ) -> Result<CgroupUsage, String> {
+        let peak_reset = peak_file.is_some();
+        let peak = read_peak(peak_file.as_mut(), dir)?;
+        let end = Snapshot::read(dir)?;
+        let window_us = (end.time - start.time).as_secs_f64() * 1e6;
+        let pressure = |s: Option<(u64, Option<u64>)>, e: Option<(u64, Option<u64>)>| match (s, e) {
+            (Some(s), Some(e)) => Some(pressure_delta(s, e, window_us)),
+            _ => None,
+        };
+
+        Ok(CgroupUsage {
+            path: dir.display().to_string(),
+            cpu: match (&start.cpu, &end.cpu) {
+                (Some(s), Some(e)) => Some(cpu_delta(s, e)),
+                _ => None,
+            },
+            memory: match (start.memory_current, end.memory_current) {
+                (Some(current_start), Some(current_end)) => Some(CgroupMemory {
+                    current_start,
+                    current_end,
+                    peak,
+                    peak_reset,
+                }),
+                _ => None,
+            },
+            io: match (&start.io, &end.io) {
+                (Some(s), Some(e)) => Some(io_delta(s, e)),
+                _ => None,
+            },
+            cpu_pressure: pressure(start.cpu_pressure, end.cpu_pressure),
+            memory_pressure: pressure(start.memory_pressure, end.memory_pressure),
+            io_pressure: pressure(start.io_pressure, end.io_pressure),
+        })
+    }
+}
+
+impl Measurement for CgroupMeasurement {
+    fn start(&mut self) {
+        self.start = self.dir.clone().and_then(|dir| {
+            if !dir.is_dir() {
+                return Err(format!("cgroup {} does not exist", dir.display()));
+            }
+            let peak = reset_peak(&dir);
+            Ok((Snapshot::read(&dir)?, peak))
+        });
+    }
+
+    fn stop(&mut self) {
+        self.result = match std::mem::replace(&mut self.start, Err(String::new())) {
+            Ok((start, peak_file)) => Self::usage(self.dir.as_ref().unwrap(), start, peak_file),
+            Err(e) => Err(e),
+        };
+    }
+
+    fn result(&mut self, _ctx: &MeasureContext) -> Measure {
+        let (value, error) = match &self.result {
+            Ok(result) => (serde_json::to_value(result).unwrap(), None),
+            Err(e) => (serde_json::Value::Null, Some(e.clone())),
+        };
+
+        Measure::new("cgroup", value, error)
+    }
+}
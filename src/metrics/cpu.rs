@@ -0,0 +1,228 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use nix::sys::resource::{getrusage, UsageWho};
+use nix::sys::time::TimeValLike;
+use serde::Serialize;
+
+use super::{Measure, MeasureContext, Measurement, ThreadHook};
+
+/// CPU time and scheduling of a benchmark thread over the run window.
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadCpu {
+    pub tid: usize,
+    pub on_cpu_ms: f64,
+    /// Time spent runnable but waiting for a CPU.
+    pub runqueue_wait_ms: f64,
+    pub timeslices: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    /// Fraction of the window the thread was on a CPU.
+    pub utilization: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CpuUsage {
+    /// User and system time of the whole process.
+    pub user_time_ms: f64,
+    pub system_time_ms: f64,
+    /// The remaining fields are summed over the benchmark threads.
+    pub on_cpu_ms: f64,
+    pub runqueue_wait_ms: f64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    /// On-CPU time of the benchmark threads as a fraction of `thread_cnt × running time`.
+    pub utilization: f64,
+    pub threads: Vec<ThreadCpu>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ThreadSnapshot {
+    run_ns: u64,
+    wait_ns: u64,
+    timeslices: u64,
+    voluntary_switches: u64,
+    involuntary_switches: u64,
+}
+
+impl ThreadSnapshot {
+    fn read(os_tid: u64) -> Result<ThreadSnapshot, String> {
+        let path = format!("/proc/self/task/{}/schedstat", os_tid);
+        let schedstat = std::fs::read_to_string(&path)
+            .map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let values: Vec<u64> = schedstat
+            .split_whitespace()
+            .map(|v| v.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid {}: {}", path, e))?;
+        if values.len() < 3 {
+            return Err(format!("invalid {}: {}", path, schedstat.trim()));
+        }
+
+        let mut snapshot = ThreadSnapshot {
+            run_ns: values[0],
+            wait_ns: values[1],
+            timeslices: values[2],
+            ..Default::default()
+        };
+
+        let path = format!("/proc/self/task/{}/status", os_tid);
+        let status = std::fs::read_to_string(&path)
+            .map_err(|e| format!("unable to read {}: {}", path, e))?;
+        for line in status.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let field = match key {
+                "voluntary_ctxt_switches" => &mut snapshot.voluntary_switches,
+                "nonvoluntary_ctxt_switches" => &mut snapshot.involuntary_switches,
+                _ => continue,
+            };
+            *field = value
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid `{}` in {}: {}", line, path, e))?;
+        }
+        Ok(snapshot)
+    }
+}
+
+/// The kernel thread id of the calling thread, `/proc/thread-self` links to `<pid>/task/<tid>`.
+fn current_os_tid() -> Result<u64, String> {
+    let link = std::fs::read_link("/proc/thread-self")
+        .map_err(|e| format!("unable to read /proc/thread-self: {}", e))?;
+    link.file_name()
+        .and_then(|tid| tid.to_str())
+        .and_then(|tid| tid.parse::<u64>().ok())
+        .ok_or_else(|| format!("invalid /proc/thread-self link: {}", link.display()))
+}
+
+/// Kernel thread ids of the benchmark threads, registered by each thread when it starts.
+#[derive(Default)]
+pub(crate) struct CpuThreads {
+    threads: Mutex<Vec<(usize, Result<u64, String>)>>,
+}
+
+impl ThreadHook for CpuThreads {
+    fn on_thread_start(&self, tid: usize) {
+        self.threads.lock().unwrap().push((tid, current_os_tid()));
+    }
+}
+
+struct Start {
+    time: Instant,
+    user_us: i64,
+    system_us: i64,
+    threads: Vec<(usize, u64, ThreadSnapshot)>,
+}
+
+pub(crate) struct CpuMeasurement {
+    threads: Arc<CpuThreads>,
+    start: Result<Start, String>,
+    result: Result<CpuUsage, String>,
+}
+
+/// User and system time of the process in microseconds, `/proc/self/stat` only counts clock ticks.
+fn process_times() -> Result<(i64, i64), String> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF).map_err(|e| format!("getrusage failed: {}", e))?;
+    Ok((
+        usage.user_time().num_microseconds(),
+        usage.system_time().num_microseconds(),
+    ))
+}
+
+impl CpuMeasurement {
+    pub(crate) fn new(threads: Arc<CpuThreads>) -> Self {
+        Self {
+            threads,
+            start: Err("the measurement was not started".to_string()),
+            result: Err("the measurement was not started".to_string()),
+        }
+    }
+
+    fn snapshot_threads(&self) -> Result<Vec<(usize, u64, ThreadSnapshot)>, String> {
+        let mut threads = self.threads.threads.lock().unwrap().clone();
+        threads.sort_by_key(|(tid, _)| *tid);
+        threads
+            .into_iter()
+            .map(|(tid, os_tid)| {
+                let os_tid = os_tid?;
+                Ok((tid, os_tid, ThreadSnapshot::read(os_tid)?))
+            })
+            .collect()
+    }
+
+    fn usage(&self, start: Start) -> Result<CpuUsage, String> {
+        let (user_us, system_us) = process_times()?;
+        let window_ns = start.time.elapsed().as_nanos() as f64;
+
+        let mut threads = Vec::new();
+        for (tid, os_tid, begin) in start.threads {
+            let end = ThreadSnapshot::read(os_tid)?;
+            let run_ns = end.run_ns.saturating_sub(begin.run_ns);
+            threads.push(ThreadCpu {
+                tid,
+                on_cpu_ms: run_ns as f64 / 1e6,
+                runqueue_wait_ms: end.wait_ns.saturating_sub(begin.wait_ns) as f64 / 1e6,
+                timeslices: end.timeslices.saturating_sub(begin.timeslices),
+                voluntary_switches: end
+                    .voluntary_switches
+                    .saturating_sub(begin.voluntary_switches),
+                involuntary_switches: end
+                    .involuntary_switches
+                    .saturating_sub(begin.involuntary_switches),
+                utilization: run_ns as f64 / window_ns,
+            });
+        }
+
+        let on_cpu_ms = threads.iter().map(|t| t.on_cpu_ms).sum::<f64>();
+        Ok(CpuUsage {
+            user_time_ms: (user_us - start.user_us) as f64 / 1000.0,
+            system_time_ms: (system_us - start.system_us) as f64 / 1000.0,
+            on_cpu_ms,
+            runqueue_wait_ms: threads.iter().map(|t| t.runqueue_wait_ms).sum(),
+            voluntary_switches: threads.iter().map(|t| t.voluntary_switches).sum(),
+            involuntary_switches: threads.iter().map(|t| t.involuntary_switches).sum(),
+            utilization: if threads.is_empty() {
+                0.0
+            } else {
+                on_cpu_ms * 1e6 / (threads.len() as f64 * window_ns)
+            },
+            threads,
+        })
+    }
+}
+
+impl Measurement for CpuMeasurement {
+    fn start(&mut self) {
+        self.start = self.snapshot_threads().and_then(|threads| {
+            let (user_us, system_us) = process_times()?;
+            Ok(Start {
+                time: Instant::now(),
+                user_us,
+                system_us,
+                threads,
+            })
+        });
+    }
+
+    fn stop(&mut self) {
+        self.result = match std::mem::replace(&mut self.start, Err(String::new())) {
+            Ok(start) => self.usage(start),
+            Err(e) => Err(e),
+        };
+    }
+
+    fn result(&mut self, _ctx: &MeasureContext) -> Measure {
+        // the benchmark threads are gone, the next iteration registers new ones
+        self.threads.threads.lock().unwrap().clear();
+
+        let (value, error) = match &self.result {
+            Ok(result) => (serde_json::to_value(result).unwrap(), None),
+            Err(e) => (serde_json::Value::Null, Some(e.clone())),
+        };
+
+        Measure::new("cpu", value, error)
+    }
+}
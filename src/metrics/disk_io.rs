@@ -1,53 +1,247 @@
-use super::{Measure, Measurement};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use super::{Measure, MeasureContext, Measurement};
+
+/// Block device from `/proc/diskstats` to report, e.g. `nvme0n1`; only the process counters are reported unless it is set.
+const DISK_DEVICE_ENV: &str = "SHUMAI_DISK_DEVICE";
+
+/// `/proc/diskstats` counts in 512 byte sectors, regardless of the device's sector size.
+const SECTOR_SIZE: u64 = 512;
+
+/// I/O of this process from `/proc/self/io`, as a delta over the benchmark window.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProcessIo {
+    /// Bytes read through read-like syscalls, including the ones served from the page cache.
+    pub rchar: u64,
+    pub wchar: u64,
+    pub syscr: u64,
+    pub syscw: u64,
+    /// Bytes actually fetched from the storage layer.
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    /// Bytes written to the page cache that were truncated before reaching the storage.
+    pub cancelled_write_bytes: u64,
+}
+
+impl ProcessIo {
+    fn read() -> Result<ProcessIo, String> {
+        let content = std::fs::read_to_string("/proc/self/io")
+            .map_err(|e| format!("unable to read /proc/self/io: {}", e))?;
+        ProcessIo::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<ProcessIo, String> {
+        let mut io = ProcessIo::default();
+        for line in content.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let value = value
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid `{}` in /proc/self/io: {}", line, e))?;
+            match key {
+                "rchar" => io.rchar = value,
+                "wchar" => io.wchar = value,
+                "syscr" => io.syscr = value,
+                "syscw" => io.syscw = value,
+                "read_bytes" => io.read_bytes = value,
+                "write_bytes" => io.write_bytes = value,
+                "cancelled_write_bytes" => io.cancelled_write_bytes = value,
+                _ => {}
+            }
+        }
+        Ok(io)
+    }
+
+    fn delta(&self, start: &ProcessIo) -> ProcessIo {
+        ProcessIo {
+            rchar: self.rchar.saturating_sub(start.rchar),
+            wchar: self.wchar.saturating_sub(start.wchar),
+            syscr: self.syscr.saturating_sub(start.syscr),
+            syscw: self.syscw.saturating_sub(start.syscw),
+            read_bytes: self.read_bytes.saturating_sub(start.read_bytes),
+            write_bytes: self.write_bytes.saturating_sub(start.write_bytes),
+            cancelled_write_bytes: self
+                .cancelled_write_bytes
+                .saturating_sub(start.cancelled_write_bytes),
+        }
+    }
+}
+
+/// The counters of a block device line in `/proc/diskstats`.
+#[derive(Debug, Clone, Default)]
+struct DiskCounters {
+    reads: u64,
+    sectors_read: u64,
+    writes: u64,
+    sectors_written: u64,
+    io_ms: u64,
+}
+
+impl DiskCounters {
+    fn read(device: &str) -> Result<DiskCounters, String> {
+        let content = std::fs::read_to_string("/proc/diskstats")
+            .map_err(|e| format!("unable to read /proc/diskstats: {}", e))?;
+        content
+            .lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>())
+            .find(|fields| fields.get(2) == Some(&device))
+            .ok_or_else(|| format!("device {} not found in /proc/diskstats", device))
+            .and_then(|fields| DiskCounters::parse(&fields))
+    }
+
+    fn parse(fields: &[&str]) -> Result<DiskCounters, String> {
+        let field = |i: usize| -> Result<u64, String> {
+            fields
+                .get(i)
+                .ok_or_else(|| format!("missing field {} in /proc/diskstats", i))?
+                .parse::<u64>()
+                .map_err(|e| format!("invalid field {} in /proc/diskstats: {}", i, e))
+        };
+        Ok(DiskCounters {
+            reads: field(3)?,
+            sectors_read: field(5)?,
+            writes: field(7)?,
+            sectors_written: field(9)?,
+            io_ms: field(12)?,
+        })
+    }
+}
+
+/// Block device I/O over the benchmark window, from all processes on the machine.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceIo {
+    pub device: String,
+    pub reads: u64,
+    pub writes: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_iops: f64,
+    pub write_iops: f64,
+    /// Bytes per second.
+    pub read_bandwidth: f64,
+    pub write_bandwidth: f64,
+    /// Fraction of the window the device had I/O in flight.
+    pub utilization: f64,
+}
+
+impl DeviceIo {
+    fn new(device: &str, start: &DiskCounters, end: &DiskCounters, secs: f64) -> DeviceIo {
+        let reads = end.reads.saturating_sub(start.reads);
+        let writes = end.writes.saturating_sub(start.writes);
+        let read_bytes = end.sectors_read.saturating_sub(start.sectors_read) * SECTOR_SIZE;
+        let write_bytes = end.sectors_written.saturating_sub(start.sectors_written) * SECTOR_SIZE;
+        let io_secs = end.io_ms.saturating_sub(start.io_ms) as f64 / 1000.0;
+        let per_sec = |v: u64| if secs > 0.0 { v as f64 / secs } else { 0.0 };
+
+        DeviceIo {
+            device: device.to_string(),
+            reads,
+            writes,
+            read_bytes,
+            write_bytes,
+            read_iops: per_sec(reads),
+            write_iops: per_sec(writes),
+            read_bandwidth: per_sec(read_bytes),
+            write_bandwidth: per_sec(write_bytes),
+            utilization: if secs > 0.0 {
+                (io_secs / secs).min(1.0)
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsage {
+    #[serde(flatten)]
+    pub process: ProcessIo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceIo>,
+}
+
+struct Snapshot {
+    time: Instant,
+    process: ProcessIo,
+    device: Option<DiskCounters>,
+}
 
 pub(crate) struct DiskIoMeasurement {
-    sys_info: sysinfo::System,
-    result: Option<DiskUsage>,
+    device: Option<String>,
+    start: Option<Snapshot>,
+    /// The snapshot of the previous sample.
+    sampled: Option<Snapshot>,
+    result: Result<DiskUsage, String>,
 }
 
 impl DiskIoMeasurement {
     pub(crate) fn new() -> Self {
         Self {
-            sys_info: sysinfo::System::new(),
-            result: None,
+            device: std::env::var(DISK_DEVICE_ENV).ok(),
+            start: None,
+            sampled: None,
+            result: Err("the measurement was not started".to_string()),
+        }
+    }
+
+    fn snapshot(&self) -> Result<Snapshot, String> {
+        Ok(Snapshot {
+            time: Instant::now(),
+            process: ProcessIo::read()?,
+            device: self.device.as_deref().map(DiskCounters::read).transpose()?,
+        })
+    }
+
+    fn usage(&self, start: &Snapshot, end: &Snapshot) -> DiskUsage {
+        let secs = (end.time - start.time).as_secs_f64();
+        DiskUsage {
+            process: end.process.delta(&start.process),
+            device: match (&self.device, &start.device, &end.device) {
+                (Some(name), Some(s), Some(e)) => Some(DeviceIo::new(name, s, e, secs)),
+                _ => None,
+            },
         }
     }
 }
 
 impl Measurement for DiskIoMeasurement {
     fn start(&mut self) {
-        self.sys_info.refresh_processes();
+        self.sampled = None;
+        match self.snapshot() {
+            Ok(s) => self.start = Some(s),
+            Err(e) => {
+                self.start = None;
+                self.result = Err(e);
+            }
+        }
     }
 
     fn stop(&mut self) {
-        let process_id = sysinfo::get_current_pid().expect("unable to get pid");
-        let process = self
-            .sys_info
-            .process(process_id)
-            .expect("unable to get process");
-        let disk_usage = process.disk_usage();
-
-        self.result = Some(DiskUsage {
-            bytes_read: disk_usage.read_bytes as usize,
-            bytes_written: disk_usage.written_bytes as usize,
-        })
+        let start = match self.start.take() {
+            Some(s) => s,
+            None => return,
+        };
+        self.result = self.snapshot().map(|end| self.usage(&start, &end));
     }
 
-    fn result(&mut self) -> Measure {
-        let value = match &self.result {
-            Some(result) => serde_json::to_value(result).unwrap(),
-            None => serde_json::Value::Null,
+    fn sample(&mut self) -> Option<serde_json::Value> {
+        let end = self.snapshot().ok()?;
+        let usage = self.usage(self.sampled.as_ref().or(self.start.as_ref())?, &end);
+        self.sampled = Some(end);
+        Some(serde_json::to_value(usage).unwrap())
+    }
+
+    fn result(&mut self, _ctx: &MeasureContext) -> Measure {
+        let (value, error) = match &self.result {
+            Ok(result) => (serde_json::to_value(result).unwrap(), None),
+            Err(e) => (serde_json::Value::Null, Some(e.clone())),
         };
 
-        Measure {
-            name: "disk_io".to_string(),
-            value,
-        }
+        Measure::new("disk_io", value, error)
     }
 }
-
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct DiskUsage {
-    pub bytes_read: usize,
-    pub bytes_written: usize,
-}
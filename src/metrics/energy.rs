@@ -0,0 +1,232 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use super::{Measure, MeasureContext, Measurement};
+
+/// Where the powercap zones are, `/sys/class/powercap` by default.
+const RAPL_PATH_ENV: &str = "SHUMAI_RAPL_PATH";
+
+/// Energy of a RAPL domain over the run window.
+#[derive(Debug, Clone, Serialize)]
+pub struct RaplDomain {
+    /// The powercap zone, e.g. `intel-rapl:0:1`.
+    pub zone: String,
+    /// The domain name, prefixed with its package for subzones, e.g. `package-0/dram`.
+    pub domain: String,
+    pub joules: f64,
+    pub watts: f64,
+    pub joules_per_op: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EnergyUsage {
+    /// Sum of the package domains, which include their core and uncore subzones.
+    pub joules: f64,
+    pub watts: f64,
+    /// `None` if the benchmark reported no operations.
+    pub joules_per_op: Option<f64>,
+    pub domains: Vec<RaplDomain>,
+    /// Zones whose counters could not be read, with the reason.
+    pub unavailable: Vec<String>,
+}
+
+/// A readable RAPL zone and its counter when the run started.
+struct Zone {
+    zone: String,
+    domain: String,
+    path: PathBuf,
+    max_energy_uj: u64,
+    start_uj: u64,
+}
+
+fn read_u64(path: &Path) -> Result<u64, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    content
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid {}: {}", path.display(), e))
+}
+
+fn read_name(path: &Path) -> Result<String, String> {
+    let path = path.join("name");
+    std::fs::read_to_string(&path)
+        .map(|n| n.trim().to_string())
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))
+}
+
+/// The energy counter wraps around at `max_energy_range_uj`, a window longer than one wrap can't be detected.
+fn energy_delta(start: u64, end: u64, max: u64) -> u64 {
+    if end >= start {
+        end - start
+    } else {
+        max.saturating_sub(start) + end
+    }
+}
+
+impl Zone {
+    fn open(root: &Path, zone: &str) -> Result<Zone, String> {
+        let path = root.join(zone);
+        let mut domain = read_name(&path)?;
+        // subzones are named after the part they measure, `intel-rapl:0:2` is the dram of `intel-rapl:0`
+        if let Some((parent, _)) = zone.rsplit_once(':').filter(|(p, _)| p.contains(':')) {
+            domain = format!("{}/{}", read_name(&root.join(parent))?, domain);
+        }
+        Ok(Zone {
+            zone: zone.to_string(),
+            domain,
+            max_energy_uj: read_u64(&path.join("max_energy_range_uj"))?,
+            start_uj: read_u64(&path.join("energy_uj"))?,
+            path,
+        })
+    }
+
+    fn is_package(&self) -> bool {
+        !self.domain.contains('/') && self.domain.starts_with("package")
+    }
+}
+
+/// Opens every RAPL zone under `root`, the ones that can't be read are returned as errors.
+fn open_zones(root: &Path) -> Result<(Vec<Zone>, Vec<String>), String> {
+    let entries =
+        std::fs::read_dir(root).map_err(|e| format!("unable to read {}: {}", root.display(), e))?;
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        // `intel-rapl-mmio` zones report the same package energy through another interface
+        .filter(|name| name.starts_with("intel-rapl:"))
+        .collect();
+    if names.is_empty() {
+        return Err(format!("no RAPL zones in {}", root.display()));
+    }
+    names.sort();
+
+    let mut zones = Vec::new();
+    let mut unavailable = Vec::new();
+    for name in names {
+        match Zone::open(root, &name) {
+            Ok(z) => zones.push(z),
+            Err(e) => unavailable.push(e),
+        }
+    }
+    if zones.is_empty() {
+        return Err(format!(
+            "no readable RAPL zones, energy_uj is only readable by root on recent kernels: {}",
+            unavailable.join("; ")
+        ));
+    }
+    Ok((zones, unavailable))
+}
+
+struct Window {
+    time: Instant,
+    zones: Vec<Zone>,
+    unavailable: Vec<String>,
+}
+
+/// The energy of every zone over the window, in microjoules.
+struct Reading {
+    secs: f64,
+    zones: Vec<(Zone, u64)>,
+    unavailable: Vec<String>,
+}
+
+pub(crate) struct EnergyMeasurement {
+    root: PathBuf,
+    start: Result<Window, String>,
+    result: Result<Reading, String>,
+}
+
+impl EnergyMeasurement {
+    pub(crate) fn new() -> Self {
+        Self {
+            root: std::env::var(RAPL_PATH_ENV)
+                .unwrap_or_else(|_| "/sys/class/powercap".to_string())
+                .into(),
+            start: Err("the measurement was not started".to_string()),
+            result: Err("the measurement was not started".to_string()),
+        }
+    }
+}
+
+impl Measurement for EnergyMeasurement {
+    fn start(&mut self) {
+        self.start = open_zones(&self.root).map(|(zones, unavailable)| Window {
+            time: Instant::now(),
+            zones,
+            unavailable,
+        });
+    }
+
+    fn stop(&mut self) {
+        let start = match std::mem::replace(&mut self.start, Err(String::new())) {
+            Ok(s) => s,
+            Err(e) => {
+                self.result = Err(e);
+                return;
+            }
+        };
+        let secs = start.time.elapsed().as_secs_f64();
+        let mut unavailable = start.unavailable;
+        let mut zones = Vec::new();
+        for zone in start.zones {
+            match read_u64(&zone.path.join("energy_uj")) {
+                Ok(end) => {
+                    let delta = energy_delta(zone.start_uj, end, zone.max_energy_uj);
+                    zones.push((zone, delta));
+                }
+                Err(e) => unavailable.push(e),
+            }
+        }
+        self.result = Ok(Reading {
+            secs,
+            zones,
+            unavailable,
+        });
+    }
+
+    fn result(&mut self, ctx: &MeasureContext) -> Measure {
+        let per_op = |joules: f64| (ctx.ops > 0).then(|| joules / ctx.ops as f64);
+
+        let (value, error) = match &self.result {
+            Ok(reading) => {
+                let secs = reading.secs;
+                let watts = |joules: f64| if secs > 0.0 { joules / secs } else { 0.0 };
+                let domains: Vec<RaplDomain> = reading
+                    .zones
+                    .iter()
+                    .map(|(zone, delta_uj)| {
+                        let joules = *delta_uj as f64 / 1e6;
+                        RaplDomain {
+                            zone: zone.zone.clone(),
+                            domain: zone.domain.clone(),
+                            joules,
+                            watts: watts(joules),
+                            joules_per_op: per_op(joules),
+                        }
+                    })
+                    .collect();
+                let joules = reading
+                    .zones
+                    .iter()
+                    .zip(&domains)
+                    .filter(|((zone, _), _)| zone.is_package())
+                    .map(|(_, d)| d.joules)
+                    .sum();
+                let usage = EnergyUsage {
+                    joules,
+                    watts: watts(joules),
+                    joules_per_op: per_op(joules),
+                    domains,
+                    unavailable: reading.unavailable.clone(),
+                };
+                (serde_json::to_value(usage).unwrap(), None)
+            }
+            Err(e) => (serde_json::Value::Null, Some(e.clone())),
+        };
+
+        Measure::new("energy", value, error)
+    }
+}
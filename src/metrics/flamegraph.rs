@@ -1,54 +1,429 @@
-use super::{Measure, Measurement};
+use super::{Measure, MeasureContext, Measurement};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Sampling frequency in Hz.
+const FLAMEGRAPH_FREQ_ENV: &str = "SHUMAI_FLAMEGRAPH_FREQ";
+/// Comma separated list of output formats: `svg`, `folded` and `pprof`.
+const FLAMEGRAPH_FORMAT_ENV: &str = "SHUMAI_FLAMEGRAPH_FORMAT";
+/// Set to `1` to merge all iterations and thread counts of a config into a single profile.
+const FLAMEGRAPH_AGGREGATE_ENV: &str = "SHUMAI_FLAMEGRAPH_AGGREGATE";
+
+const DEFAULT_FREQ: i32 = 199;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Svg,
+    /// Collapsed stacks, one `frame;frame;frame count` line per stack, as read by inferno and flamegraph.pl.
+    Folded,
+    /// pprof protobuf, for `go tool pprof` and friends.
+    Pprof,
+}
+
+impl Format {
+    fn parse(s: &str) -> Format {
+        match s {
+            "svg" => Format::Svg,
+            "folded" => Format::Folded,
+            "pprof" => Format::Pprof,
+            _ => panic!(
+                "Unknown flamegraph format `{}` in {}, expected svg, folded or pprof",
+                s, FLAMEGRAPH_FORMAT_ENV
+            ),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Format::Svg => "svg",
+            Format::Folded => "folded",
+            Format::Pprof => "pprof",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Svg => "svg",
+            Format::Folded => "folded",
+            Format::Pprof => "pb",
+        }
+    }
+}
+
+/// The folded stacks are always kept next to an svg, so that runs can be compared with [`diff`].
+fn configured_formats() -> Vec<Format> {
+    let mut formats: Vec<_> = match std::env::var(FLAMEGRAPH_FORMAT_ENV) {
+        Ok(formats) => formats
+            .split(',')
+            .map(|f| f.trim())
+            .filter(|f| !f.is_empty())
+            .map(Format::parse)
+            .collect(),
+        Err(_) => vec![Format::Svg],
+    };
+    if formats.contains(&Format::Svg) && !formats.contains(&Format::Folded) {
+        formats.push(Format::Folded);
+    }
+    formats
+}
+
+/// Config names may contain characters that don't belong in a file name, e.g. from enum matrix values.
+fn file_name(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn folded(report: &pprof::Report) -> String {
+    let mut lines: Vec<_> = report
+        .data
+        .iter()
+        .map(|(frames, count)| {
+            let mut line = frames.thread_name_or_id();
+            for frame in frames.frames.iter().rev() {
+                for symbol in frame.iter().rev() {
+                    line.push(';');
+                    line.push_str(&symbol.to_string());
+                }
+            }
+            format!("{} {}", line, count)
+        })
+        .collect();
+    lines.sort();
+    lines.join("\n")
+}
 
 pub(crate) struct FlamegraphMeasurement<'a> {
     guard: Option<pprof::ProfilerGuard<'a>>,
     report: Option<pprof::Report>,
+    frequency: i32,
+    formats: Vec<Format>,
+    aggregate: bool,
+    /// All files of a run share the directory and time of when the run started.
+    prefix: String,
 }
 
 impl<'a> FlamegraphMeasurement<'a> {
     pub(crate) fn new() -> Self {
+        use chrono::{Datelike, Local, Timelike};
+
+        let local_time = Local::now();
+        let frequency = match std::env::var(FLAMEGRAPH_FREQ_ENV) {
+            Ok(f) => f
+                .parse::<i32>()
+                .ok()
+                .filter(|f| *f > 0)
+                .expect("SHUMAI_FLAMEGRAPH_FREQ must be a positive number"),
+            Err(_) => DEFAULT_FREQ,
+        };
+        let aggregate = matches!(
+            std::env::var(FLAMEGRAPH_AGGREGATE_ENV).as_deref(),
+            Ok("1") | Ok("true")
+        );
+
         Self {
             guard: None,
             report: None,
+            frequency,
+            formats: configured_formats(),
+            aggregate,
+            prefix: format!(
+                "target/benchmark/{}-{:02}-{:02}/{:02}-{:02}-{:02}",
+                local_time.year(),
+                local_time.month(),
+                local_time.day(),
+                local_time.hour(),
+                local_time.minute(),
+                local_time.second()
+            ),
+        }
+    }
+
+    fn path(&self, ctx: &MeasureContext, format: Format) -> PathBuf {
+        let name = if self.aggregate {
+            file_name(&ctx.config_name)
+        } else {
+            format!(
+                "{}-{}t-iter{}",
+                file_name(&ctx.config_name),
+                ctx.thread_cnt,
+                ctx.iteration
+            )
+        };
+        PathBuf::from(format!("{}-{}.{}", self.prefix, name, format.extension()))
+    }
+
+    fn write(&self, report: &pprof::Report, format: Format, path: &PathBuf) {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        let mut file = std::fs::File::create(path).unwrap();
+        match format {
+            Format::Svg => report.flamegraph(file).unwrap(),
+            Format::Folded => file.write_all(folded(report).as_bytes()).unwrap(),
+            Format::Pprof => {
+                use pprof::protos::Message;
+
+                let profile = report.pprof().unwrap();
+                file.write_all(&profile.encode_to_vec()).unwrap();
+            }
         }
     }
 }
 
 impl<'a> Measurement for FlamegraphMeasurement<'a> {
     fn start(&mut self) {
-        self.guard = Some(pprof::ProfilerGuard::new(199).unwrap());
+        self.guard = Some(pprof::ProfilerGuard::new(self.frequency).unwrap());
     }
 
     fn stop(&mut self) {
         let guard = self.guard.take().unwrap();
         let report = guard.report().build().unwrap();
-        self.report = Some(report);
+        match self.report.as_mut() {
+            // later iterations are merged into the report of the first one
+            Some(aggregated) if self.aggregate => {
+                for (frames, count) in report.data {
+                    *aggregated.data.entry(frames).or_insert(0) += count;
+                }
+                aggregated.timing.duration += report.timing.duration;
+            }
+            _ => self.report = Some(report),
+        }
     }
 
-    fn result(&mut self) -> Measure {
-        use chrono::{Datelike, Local, Timelike};
-        use std::str::FromStr;
+    fn result(&mut self, ctx: &MeasureContext) -> Measure {
+        // the profile of a calibration iteration is dropped with the rest of its result
+        if ctx.calibrating {
+            self.report = None;
+            return Measure::new("flamegraph", serde_json::Value::Null, None);
+        }
 
-        let local_time = Local::now();
-        let path = std::path::PathBuf::from_str(&format!(
-            "target/benchmark/{}-{:02}-{:02}/{:02}-{:02}-{:02}.svg",
-            local_time.year(),
-            local_time.month(),
-            local_time.day(),
-            local_time.hour(),
-            local_time.minute(),
-            local_time.second()
-        ))
-        .unwrap();
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent).unwrap();
+        // in aggregate mode the files are rewritten after every iteration, and complete after the last one
+        let report = if self.aggregate {
+            self.report.as_ref().unwrap()
+        } else {
+            &self.report.take().unwrap()
+        };
+
+        let mut paths = BTreeMap::new();
+        for format in self.formats.iter() {
+            let path = self.path(ctx, *format);
+            self.write(report, *format, &path);
+            paths.insert(format.name(), path.to_str().unwrap().to_string());
         }
-        let file = std::fs::File::create(path.clone()).unwrap();
-        let report = self.report.take().unwrap();
-        report.flamegraph(file).unwrap();
-        Measure {
-            name: "flamegraph".to_string(),
-            value: serde_json::Value::String(path.to_str().unwrap().to_string()),
+
+        let mut value = serde_json::to_value(paths).unwrap();
+        if self.aggregate {
+            value["aggregate"] = true.into();
+        }
+
+        Measure::new("flamegraph", value, None)
+    }
+}
+
+/// Errors from [`diff`].
+#[derive(Debug)]
+pub enum DiffError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file is not a shumai result.
+    Parse { path: PathBuf, message: String },
+    /// The result files are for different configs.
+    ConfigMismatch { baseline: String, candidate: String },
+    /// The result file has no iterations with folded stacks for the thread count.
+    MissingProfile { path: PathBuf, thread_cnt: usize },
+    /// The result file was profiled with `SHUMAI_FLAMEGRAPH_AGGREGATE`, its profile merges all thread counts.
+    Aggregated { path: PathBuf },
+}
+
+impl Display for DiffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiffError::Io { path, source } => {
+                write!(f, "failed to access {}: {}", path.display(), source)
+            }
+            DiffError::Parse { path, message } => write!(
+                f,
+                "{} is not a benchmark result file: {}",
+                path.display(),
+                message
+            ),
+            DiffError::ConfigMismatch {
+                baseline,
+                candidate,
+            } => write!(
+                f,
+                "can't compare different configs, baseline is {} but candidate is {}",
+                baseline, candidate
+            ),
+            DiffError::MissingProfile { path, thread_cnt } => write!(
+                f,
+                "{} has no folded flamegraph for {} threads, was it run with the flamegraph feature?",
+                path.display(),
+                thread_cnt
+            ),
+            DiffError::Aggregated { path } => write!(
+                f,
+                "{} was profiled with {}, its profile merges all thread counts and can't be compared per thread count",
+                path.display(),
+                FLAMEGRAPH_AGGREGATE_ENV
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiffError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiffError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String, DiffError> {
+    std::fs::read_to_string(path).map_err(|source| DiffError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Paths in a result file are relative to where the benchmark ran,
+/// fall back to the directory of the result file in case it was moved along with its profiles.
+fn locate(result: &Path, folded: &str) -> PathBuf {
+    let path = PathBuf::from(folded);
+    match (path.exists(), result.parent(), path.file_name()) {
+        (false, Some(dir), Some(name)) if dir.join(name).exists() => dir.join(name),
+        _ => path,
+    }
+}
+
+/// The config name and the folded stacks of all iterations with `thread_cnt` threads, summed.
+/// Aggregated profiles are rejected, as the thread counts can't be told apart in them.
+fn load_profile(
+    path: &Path,
+    thread_cnt: usize,
+) -> Result<(String, BTreeMap<String, u64>), DiffError> {
+    let parse_error = |message: String| DiffError::Parse {
+        path: path.to_path_buf(),
+        message,
+    };
+    let result: serde_json::Value =
+        serde_json::from_str(&read(path)?).map_err(|e| parse_error(e.to_string()))?;
+    let name = result["config"]["name"]
+        .as_str()
+        .ok_or_else(|| parse_error("missing config name".to_string()))?
+        .to_string();
+    let runs = result["run"]
+        .as_array()
+        .ok_or_else(|| parse_error("missing run results".to_string()))?;
+
+    let profiles: Vec<_> = runs
+        .iter()
+        .filter_map(|r| r["iterations"].as_array().map(|i| (r, i)))
+        .flat_map(|(r, iterations)| iterations.iter().map(move |i| (r, i)))
+        .filter_map(|(r, i)| i["measurements"].as_array().map(|m| (r, m)))
+        .flat_map(|(r, measurements)| measurements.iter().map(move |m| (r, m)))
+        .filter(|(_, m)| m["name"] == "flamegraph")
+        .collect();
+    if profiles
+        .iter()
+        .any(|(_, m)| m["value"]["aggregate"] == true)
+    {
+        return Err(DiffError::Aggregated {
+            path: path.to_path_buf(),
+        });
+    }
+
+    // a profile is only counted once, even if several iterations point to the same file
+    let folded_files: BTreeSet<&str> = profiles
+        .iter()
+        .filter(|(r, _)| r["thread_cnt"].as_u64() == Some(thread_cnt as u64))
+        .filter_map(|(_, m)| m["value"]["folded"].as_str())
+        .collect();
+
+    let mut stacks = BTreeMap::new();
+    for folded in folded_files {
+        for line in read(&locate(path, folded))?.lines() {
+            if let Some((stack, count)) = line.rsplit_once(' ') {
+                let count = count
+                    .parse::<u64>()
+                    .map_err(|e| parse_error(format!("bad folded stack `{}`: {}", line, e)))?;
+                *stacks.entry(stack.to_string()).or_insert(0) += count;
+            }
         }
     }
+
+    if stacks.is_empty() {
+        return Err(DiffError::MissingProfile {
+            path: path.to_path_buf(),
+            thread_cnt,
+        });
+    }
+    Ok((name, stacks))
+}
+
+fn to_folded(stacks: &BTreeMap<String, u64>) -> String {
+    stacks
+        .iter()
+        .map(|(stack, count)| format!("{} {}\n", stack, count))
+        .collect()
+}
+
+/// Writes a differential flamegraph of `candidate` against `baseline` to `output`.
+///
+/// Both are result files written by [`crate::ShumaiResult::write_json`] for the same config,
+/// the profiles of all iterations with `thread_cnt` threads are summed.
+/// Results profiled with `SHUMAI_FLAMEGRAPH_AGGREGATE` can't be compared, their profile merges every thread count.
+/// Frames that got hotter in the candidate are red, the ones that got colder are blue.
+pub fn diff(
+    baseline: &Path,
+    candidate: &Path,
+    thread_cnt: usize,
+    output: &Path,
+) -> Result<(), DiffError> {
+    let (baseline_name, before) = load_profile(baseline, thread_cnt)?;
+    let (candidate_name, after) = load_profile(candidate, thread_cnt)?;
+    if baseline_name != candidate_name {
+        return Err(DiffError::ConfigMismatch {
+            baseline: baseline_name,
+            candidate: candidate_name,
+        });
+    }
+
+    let io_error = |source| DiffError::Io {
+        path: output.to_path_buf(),
+        source,
+    };
+    let mut folded = Vec::new();
+    inferno::differential::from_readers(
+        inferno::differential::Options::default(),
+        to_folded(&before).as_bytes(),
+        to_folded(&after).as_bytes(),
+        &mut folded,
+    )
+    .map_err(io_error)?;
+
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent).map_err(io_error)?;
+    }
+    let file = std::fs::File::create(output).map_err(io_error)?;
+    let mut options = inferno::flamegraph::Options::default();
+    options.title = format!("{} ({} threads)", candidate_name, thread_cnt);
+    inferno::flamegraph::from_reader(&mut options, folded.as_slice(), file).map_err(|e| {
+        DiffError::Io {
+            path: output.to_path_buf(),
+            source: std::io::Error::other(e),
+        }
+    })
 }
@@ -0,0 +1,178 @@
+use nix::sys::resource::{getrusage, UsageWho};
+use serde::Serialize;
+
+use super::{Measure, MeasureContext, Measurement};
+
+/// Memory of the benchmark process, in bytes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryUsage {
+    pub rss_start: u64,
+    pub rss_end: u64,
+    /// Peak RSS (`VmHWM`) during the run window if `peak_reset`, otherwise since the process started.
+    pub peak_rss: u64,
+    /// Whether the peak could be reset through `/proc/self/clear_refs` when the run started.
+    pub peak_reset: bool,
+    /// Anonymous, file-backed and shared memory at the end of the run.
+    pub rss_anon: u64,
+    pub rss_file: u64,
+    pub rss_shmem: u64,
+    pub minor_faults: u64,
+    pub major_faults: u64,
+}
+
+/// RSS when sampled, and the page faults since the previous sample.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemorySample {
+    pub rss: u64,
+    pub rss_anon: u64,
+    pub minor_faults: u64,
+    pub major_faults: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Status {
+    rss: u64,
+    hwm: u64,
+    rss_anon: u64,
+    rss_file: u64,
+    rss_shmem: u64,
+}
+
+impl Status {
+    fn read() -> Result<Status, String> {
+        let content = std::fs::read_to_string("/proc/self/status")
+            .map_err(|e| format!("unable to read /proc/self/status: {}", e))?;
+
+        let mut status = Status::default();
+        for line in content.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let field = match key {
+                "VmRSS" => &mut status.rss,
+                "VmHWM" => &mut status.hwm,
+                "RssAnon" => &mut status.rss_anon,
+                "RssFile" => &mut status.rss_file,
+                "RssShmem" => &mut status.rss_shmem,
+                _ => continue,
+            };
+            // the values are in kB, e.g. `VmRSS:	    2048 kB`
+            let kb = value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid `{}` in /proc/self/status: {}", line, e))?;
+            *field = kb * 1024;
+        }
+        Ok(status)
+    }
+}
+
+/// Minor and major page faults of the process.
+fn read_faults() -> Result<(u64, u64), String> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF).map_err(|e| format!("getrusage failed: {}", e))?;
+    Ok((
+        usage.minor_page_faults() as u64,
+        usage.major_page_faults() as u64,
+    ))
+}
+
+/// Resets the peak RSS to the current RSS, not allowed in some containers.
+fn reset_peak() -> bool {
+    std::fs::write("/proc/self/clear_refs", "5").is_ok()
+}
+
+struct Start {
+    rss: u64,
+    peak_reset: bool,
+    minor_faults: u64,
+    major_faults: u64,
+}
+
+pub(crate) struct MemoryMeasurement {
+    start: Result<Start, String>,
+    /// The minor and major page faults at the previous sample.
+    sampled: Option<(u64, u64)>,
+    result: Result<MemoryUsage, String>,
+}
+
+impl MemoryMeasurement {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Err("the measurement was not started".to_string()),
+            sampled: None,
+            result: Err("the measurement was not started".to_string()),
+        }
+    }
+}
+
+impl Measurement for MemoryMeasurement {
+    fn start(&mut self) {
+        let peak_reset = reset_peak();
+        self.start = Status::read().and_then(|status| {
+            let (minor_faults, major_faults) = read_faults()?;
+            Ok(Start {
+                rss: status.rss,
+                peak_reset,
+                minor_faults,
+                major_faults,
+            })
+        });
+        self.sampled = self
+            .start
+            .as_ref()
+            .ok()
+            .map(|s| (s.minor_faults, s.major_faults));
+    }
+
+    fn sample(&mut self) -> Option<serde_json::Value> {
+        let (last_minor, last_major) = self.sampled?;
+        let status = Status::read().ok()?;
+        let (minor_faults, major_faults) = read_faults().ok()?;
+        self.sampled = Some((minor_faults, major_faults));
+        Some(
+            serde_json::to_value(MemorySample {
+                rss: status.rss,
+                rss_anon: status.rss_anon,
+                minor_faults: minor_faults.saturating_sub(last_minor),
+                major_faults: major_faults.saturating_sub(last_major),
+            })
+            .unwrap(),
+        )
+    }
+
+    fn stop(&mut self) {
+        let start = match std::mem::replace(&mut self.start, Err(String::new())) {
+            Ok(s) => s,
+            Err(e) => {
+                self.result = Err(e);
+                return;
+            }
+        };
+        self.result = Status::read().and_then(|status| {
+            let (minor_faults, major_faults) = read_faults()?;
+            Ok(MemoryUsage {
+                rss_start: start.rss,
+                rss_end: status.rss,
+                peak_rss: status.hwm,
+                peak_reset: start.peak_reset,
+                rss_anon: status.rss_anon,
+                rss_file: status.rss_file,
+                rss_shmem: status.rss_shmem,
+                minor_faults: minor_faults.saturating_sub(start.minor_faults),
+                major_faults: major_faults.saturating_sub(start.major_faults),
+            })
+        });
+    }
+
+    fn result(&mut self, _ctx: &MeasureContext) -> Measure {
+        let (value, error) = match &self.result {
+            Ok(result) => (serde_json::to_value(result).unwrap(), None),
+            Err(e) => (serde_json::Value::Null, Some(e.clone())),
+        };
+
+        Measure::new("memory", value, error)
+    }
+}
@@ -1,24 +1,276 @@
+use std::sync::Arc;
+
 use serde::Serialize;
 
+#[cfg(feature = "alloc")]
+pub(crate) mod alloc;
+pub(crate) mod cgroup;
+pub(crate) mod cpu;
 pub(crate) mod disk_io;
+pub(crate) mod energy;
 #[cfg(feature = "flamegraph")]
 pub(crate) mod flamegraph;
+pub(crate) mod memory;
+pub(crate) mod net_io;
 
 #[cfg(feature = "pcm")]
 pub(crate) mod pcm;
 
 #[cfg(feature = "perf")]
 pub(crate) mod perf;
+#[cfg(feature = "perf")]
+pub(crate) mod perf_sample;
+pub(crate) mod system;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Measure {
     name: String,
     value: serde_json::Value,
+    /// Why the measurement failed, `value` holds whatever was collected before the failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Samples covering the run, from the collector's own sampler or from `Measurement::sample`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) series: Vec<MeasureSample>,
+}
+
+impl Measure {
+    pub(crate) fn new(name: &str, value: serde_json::Value, error: Option<String>) -> Measure {
+        Measure {
+            name: name.to_string(),
+            value,
+            error,
+            series: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_series(mut self, series: Vec<MeasureSample>) -> Measure {
+        self.series = series;
+        self
+    }
+}
+
+/// A sample of a measurement covering `[start_ms, end_ms]` of the run window.
+#[derive(Debug, Clone, Serialize)]
+pub struct MeasureSample {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub value: serde_json::Value,
+}
+
+/// What the runner knows about the finished iteration when collecting the results.
+pub(crate) struct MeasureContext {
+    /// Total operations of all threads in the run window, summed from `BenchResult::short_value`.
+    pub(crate) ops: usize,
+    /// Operations of each thread, indexed by the thread id.
+    #[cfg_attr(not(feature = "perf"), allow(dead_code))]
+    pub(crate) thread_ops: Vec<usize>,
+    pub(crate) config_name: String,
+    pub(crate) thread_cnt: usize,
+    pub(crate) iteration: usize,
+    /// The iteration only measures the overhead of the measurement, its result is dropped.
+    #[cfg_attr(not(feature = "flamegraph"), allow(dead_code))]
+    pub(crate) calibrating: bool,
 }
 
 pub(crate) trait Measurement {
     fn start(&mut self) {}
     fn stop(&mut self) {}
 
-    fn result(&mut self) -> Measure;
+    /// Called periodically by the runner between `start` and `stop` when `SHUMAI_SAMPLE_INTERVAL_MS` is set,
+    /// and once more when the run stops, returns what changed since the previous sample (or `start`),
+    /// `None` if the measurement is not sampled.
+    fn sample(&mut self) -> Option<serde_json::Value> {
+        None
+    }
+
+    fn result(&mut self, ctx: &MeasureContext) -> Measure;
+}
+
+/// Called by every benchmark thread before it reports ready,
+/// for measurements that have to be set up by the measured thread itself.
+pub(crate) trait ThreadHook: Send + Sync {
+    fn on_thread_start(&self, tid: usize);
+}
+
+/// Reads numeric fields of a `/proc/<pid>/stat` file, numbered from 1 as in `man proc`.
+pub(crate) fn read_stat_fields(path: &str, fields: &[usize]) -> Result<Vec<u64>, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    // the command name may contain spaces, the fields after it start from the state (field 3)
+    let values: Vec<_> = content
+        .rsplit_once(')')
+        .map(|(_, rest)| rest.split_whitespace().collect())
+        .unwrap_or_default();
+    fields
+        .iter()
+        .map(|n| {
+            values
+                .get(n - 3)
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or_else(|| format!("invalid field {} in {}", n, path))
+        })
+        .collect()
+}
+
+/// Comma separated list of measurements to record, the default-on entries of `MEASUREMENTS` unless it is set.
+const MEASURE_ENV: &str = "SHUMAI_MEASURE";
+
+/// Every measurement, the cargo feature it needs, and whether it is recorded without `SHUMAI_MEASURE`.
+///
+/// Only the snapshots taken when the run starts and stops are recorded by default, collectors that
+/// sample or profile the benchmark while it runs perturb it and have to be selected.
+///
+/// Measurements start in this order and stop in the reverse one: the profilers, whose stop builds
+/// their reports, wrap the samplers, which wrap the snapshots, so every window ends right after the run.
+const MEASUREMENTS: &[(&str, Option<&str>, bool)] = &[
+    ("flamegraph", Some("flamegraph"), false),
+    ("perf", Some("perf"), false),
+    ("perf_sample", Some("perf"), false),
+    ("system", None, false),
+    ("pcm", Some("pcm"), false),
+    ("disk_io", None, true),
+    ("net_io", None, true),
+    ("memory", None, true),
+    ("cpu", None, true),
+    ("cgroup", None, true),
+    ("energy", None, true),
+    ("alloc", Some("alloc"), true),
+];
+
+fn compiled_in(feature: Option<&str>) -> bool {
+    match feature {
+        None => true,
+        Some("flamegraph") => cfg!(feature = "flamegraph"),
+        Some("perf") => cfg!(feature = "perf"),
+        Some("pcm") => cfg!(feature = "pcm"),
+        Some("alloc") => cfg!(feature = "alloc"),
+        Some(_) => false,
+    }
+}
+
+/// The measurements selected by `SHUMAI_MEASURE`, `None` for the default ones.
+fn selected_measurements() -> Option<Vec<String>> {
+    let selected: Vec<String> = std::env::var(MEASURE_ENV)
+        .ok()?
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty() && m != "none")
+        .collect();
+    for name in selected.iter() {
+        match MEASUREMENTS.iter().find(|(m, _, _)| m == name) {
+            Some((_, feature, _)) if !compiled_in(*feature) => panic!(
+                "SHUMAI_MEASURE: measurement {} needs the `{}` cargo feature",
+                name,
+                feature.unwrap()
+            ),
+            Some(_) => {}
+            None => panic!(
+                "SHUMAI_MEASURE: unknown measurement {}, expected a comma separated list of {} or none",
+                name,
+                MEASUREMENTS
+                    .iter()
+                    .map(|(m, _, _)| *m)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+    Some(selected)
+}
+
+/// The measurements of a benchmark, and the hooks their benchmark threads have to run.
+#[derive(Default)]
+pub(crate) struct MeasurementSet {
+    pub(crate) measurements: Vec<Box<dyn Measurement>>,
+    pub(crate) thread_hooks: Vec<Arc<dyn ThreadHook>>,
+}
+
+/// Creates the measurements selected by `SHUMAI_MEASURE`.
+pub(crate) fn from_env() -> MeasurementSet {
+    let names = selected_names();
+    build(|name| names.contains(&name))
+}
+
+/// Names of the compiled-in measurements selected by `SHUMAI_MEASURE`, in the order they are recorded.
+pub(crate) fn selected_names() -> Vec<&'static str> {
+    let selected = selected_measurements();
+    MEASUREMENTS
+        .iter()
+        .filter(|(name, feature, default)| {
+            compiled_in(*feature)
+                && match &selected {
+                    Some(s) => s.iter().any(|m| m == name),
+                    None => *default,
+                }
+        })
+        .map(|(name, _, _)| *name)
+        .collect()
+}
+
+/// Creates the measurement `name` alone, the set is empty if it is not configured (e.g. `perf_sample`).
+pub(crate) fn only(name: &str) -> MeasurementSet {
+    build(|m| m == name)
+}
+
+fn build(enabled: impl Fn(&str) -> bool) -> MeasurementSet {
+    let mut measurements: Vec<Box<dyn Measurement>> = Vec::new();
+    let mut thread_hooks: Vec<Arc<dyn ThreadHook>> = Vec::new();
+
+    #[cfg(feature = "flamegraph")]
+    if enabled("flamegraph") {
+        measurements.push(Box::new(flamegraph::FlamegraphMeasurement::new()));
+    }
+    #[cfg(feature = "perf")]
+    if enabled("perf") {
+        let perf_threads = perf::ThreadCounters::from_env();
+        if let Some(t) = &perf_threads {
+            thread_hooks.push(t.clone());
+        }
+        measurements.push(Box::new(perf::PerfMeasurement::new(perf_threads)));
+    }
+    // sampling also needs `SHUMAI_PERF_SAMPLE` to know which event to sample
+    #[cfg(feature = "perf")]
+    if enabled("perf_sample") {
+        if let Some(samplers) = perf_sample::Samplers::from_env() {
+            thread_hooks.push(samplers.clone());
+            measurements.push(Box::new(perf_sample::PerfSampleMeasurement::new(samplers)));
+        }
+    }
+    if enabled("system") {
+        measurements.push(Box::new(system::SystemMeasurement::new()));
+    }
+    #[cfg(feature = "pcm")]
+    if enabled("pcm") {
+        measurements.push(Box::new(pcm::PcmMeasurement::new()));
+    }
+    if enabled("disk_io") {
+        measurements.push(Box::new(disk_io::DiskIoMeasurement::new()));
+    }
+    if enabled("net_io") {
+        measurements.push(Box::new(net_io::NetIoMeasurement::new()));
+    }
+    if enabled("memory") {
+        measurements.push(Box::new(memory::MemoryMeasurement::new()));
+    }
+    if enabled("cpu") {
+        let cpu_threads = Arc::new(cpu::CpuThreads::default());
+        thread_hooks.push(cpu_threads.clone());
+        measurements.push(Box::new(cpu::CpuMeasurement::new(cpu_threads)));
+    }
+    if enabled("cgroup") {
+        measurements.push(Box::new(cgroup::CgroupMeasurement::new()));
+    }
+    if enabled("energy") {
+        measurements.push(Box::new(energy::EnergyMeasurement::new()));
+    }
+    #[cfg(feature = "alloc")]
+    if enabled("alloc") {
+        measurements.push(Box::new(alloc::AllocMeasurement::new()));
+    }
+
+    MeasurementSet {
+        measurements,
+        thread_hooks,
+    }
 }
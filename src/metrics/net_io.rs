@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use super::{Measure, MeasureContext, Measurement};
+
+/// Comma separated list of interfaces from `/proc/net/dev` to report, e.g. `lo`; all interfaces unless it is set.
+const NET_INTERFACE_ENV: &str = "SHUMAI_NET_INTERFACE";
+
+/// Counters of an interface line in `/proc/net/dev`, as a delta over the benchmark window.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InterfaceCounters {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_drops: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_drops: u64,
+}
+
+impl InterfaceCounters {
+    fn parse(line: &str) -> Result<(String, InterfaceCounters), String> {
+        let (name, values) = line
+            .split_once(':')
+            .ok_or_else(|| format!("invalid `{}` in /proc/net/dev", line.trim()))?;
+        let values: Vec<u64> = values
+            .split_whitespace()
+            .map(|v| v.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid `{}` in /proc/net/dev: {}", line.trim(), e))?;
+        if values.len() < 12 {
+            return Err(format!("invalid `{}` in /proc/net/dev", line.trim()));
+        }
+        Ok((
+            name.trim().to_string(),
+            InterfaceCounters {
+                rx_bytes: values[0],
+                rx_packets: values[1],
+                rx_errors: values[2],
+                rx_drops: values[3],
+                tx_bytes: values[8],
+                tx_packets: values[9],
+                tx_errors: values[10],
+                tx_drops: values[11],
+            },
+        ))
+    }
+
+    /// Reads the interfaces of the network namespace, in the order of `/proc/net/dev`.
+    fn read() -> Result<Vec<(String, InterfaceCounters)>, String> {
+        let content = std::fs::read_to_string("/proc/net/dev")
+            .map_err(|e| format!("unable to read /proc/net/dev: {}", e))?;
+        // the first two lines are the header
+        content
+            .lines()
+            .skip(2)
+            .map(InterfaceCounters::parse)
+            .collect()
+    }
+
+    fn delta(&self, start: &InterfaceCounters) -> InterfaceCounters {
+        InterfaceCounters {
+            rx_bytes: self.rx_bytes.saturating_sub(start.rx_bytes),
+            rx_packets: self.rx_packets.saturating_sub(start.rx_packets),
+            rx_errors: self.rx_errors.saturating_sub(start.rx_errors),
+            rx_drops: self.rx_drops.saturating_sub(start.rx_drops),
+            tx_bytes: self.tx_bytes.saturating_sub(start.tx_bytes),
+            tx_packets: self.tx_packets.saturating_sub(start.tx_packets),
+            tx_errors: self.tx_errors.saturating_sub(start.tx_errors),
+            tx_drops: self.tx_drops.saturating_sub(start.tx_drops),
+        }
+    }
+
+    fn add(&mut self, other: &InterfaceCounters) {
+        self.rx_bytes += other.rx_bytes;
+        self.rx_packets += other.rx_packets;
+        self.rx_errors += other.rx_errors;
+        self.rx_drops += other.rx_drops;
+        self.tx_bytes += other.tx_bytes;
+        self.tx_packets += other.tx_packets;
+        self.tx_errors += other.tx_errors;
+        self.tx_drops += other.tx_drops;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceIo {
+    pub interface: String,
+    #[serde(flatten)]
+    pub counters: InterfaceCounters,
+    /// Bytes per second.
+    pub rx_bandwidth: f64,
+    pub tx_bandwidth: f64,
+}
+
+/// TCP and UDP counters of `/proc/net/snmp`, they cover every interface of the network namespace.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProtocolIo {
+    pub tcp_in_segs: u64,
+    pub tcp_out_segs: u64,
+    pub tcp_retrans_segs: u64,
+    pub tcp_in_errs: u64,
+    pub tcp_out_rsts: u64,
+    /// Retransmitted segments as a fraction of the segments sent.
+    pub tcp_retransmit_rate: f64,
+    pub udp_in_datagrams: u64,
+    pub udp_out_datagrams: u64,
+    pub udp_in_errors: u64,
+    pub udp_rcvbuf_errors: u64,
+    pub udp_sndbuf_errors: u64,
+}
+
+/// The `Tcp` and `Udp` tables of `/proc/net/snmp`, each a header line followed by a value line.
+#[derive(Debug, Clone, Default)]
+struct Snmp(HashMap<String, u64>);
+
+impl Snmp {
+    fn read() -> Result<Snmp, String> {
+        let content = std::fs::read_to_string("/proc/net/snmp")
+            .map_err(|e| format!("unable to read /proc/net/snmp: {}", e))?;
+        let mut counters = HashMap::new();
+        let mut headers: HashMap<&str, &str> = HashMap::new();
+        for line in content.lines() {
+            let (table, rest) = line.split_once(':').unwrap_or_default();
+            if table != "Tcp" && table != "Udp" {
+                continue;
+            }
+            let names = match headers.remove(table) {
+                Some(names) => names,
+                None => {
+                    headers.insert(table, rest);
+                    continue;
+                }
+            };
+            for (name, value) in names.split_whitespace().zip(rest.split_whitespace()) {
+                // some values are signed, e.g. `MaxConn` is -1, the counters never are
+                if let Ok(v) = value.parse::<u64>() {
+                    counters.insert(format!("{}{}", table, name), v);
+                }
+            }
+        }
+        Ok(Snmp(counters))
+    }
+
+    fn delta(&self, start: &Snmp, name: &str) -> u64 {
+        let get = |s: &Snmp| s.0.get(name).copied().unwrap_or(0);
+        get(self).saturating_sub(get(start))
+    }
+
+    fn protocol_io(&self, start: &Snmp) -> ProtocolIo {
+        let tcp_out_segs = self.delta(start, "TcpOutSegs");
+        let tcp_retrans_segs = self.delta(start, "TcpRetransSegs");
+        ProtocolIo {
+            tcp_in_segs: self.delta(start, "TcpInSegs"),
+            tcp_out_segs,
+            tcp_retrans_segs,
+            tcp_in_errs: self.delta(start, "TcpInErrs"),
+            tcp_out_rsts: self.delta(start, "TcpOutRsts"),
+            tcp_retransmit_rate: if tcp_out_segs > 0 {
+                tcp_retrans_segs as f64 / tcp_out_segs as f64
+            } else {
+                0.0
+            },
+            udp_in_datagrams: self.delta(start, "UdpInDatagrams"),
+            udp_out_datagrams: self.delta(start, "UdpOutDatagrams"),
+            udp_in_errors: self.delta(start, "UdpInErrors"),
+            udp_rcvbuf_errors: self.delta(start, "UdpRcvbufErrors"),
+            udp_sndbuf_errors: self.delta(start, "UdpSndbufErrors"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetUsage {
+    /// Sum of the reported interfaces.
+    #[serde(flatten)]
+    pub total: InterfaceCounters,
+    pub interfaces: Vec<InterfaceIo>,
+    #[serde(flatten)]
+    pub protocols: ProtocolIo,
+}
+
+struct Snapshot {
+    time: Instant,
+    interfaces: Vec<(String, InterfaceCounters)>,
+    snmp: Snmp,
+}
+
+pub(crate) struct NetIoMeasurement {
+    interfaces: Option<Vec<String>>,
+    start: Option<Snapshot>,
+    result: Result<NetUsage, String>,
+}
+
+impl NetIoMeasurement {
+    pub(crate) fn new() -> Self {
+        Self {
+            interfaces: std::env::var(NET_INTERFACE_ENV)
+                .ok()
+                .map(|s| s.split(',').map(|i| i.trim().to_string()).collect()),
+            start: None,
+            result: Err("the measurement was not started".to_string()),
+        }
+    }
+
+    fn snapshot(&self) -> Result<Snapshot, String> {
+        let mut interfaces = InterfaceCounters::read()?;
+        if let Some(selected) = &self.interfaces {
+            if let Some(missing) = selected
+                .iter()
+                .find(|s| !interfaces.iter().any(|(name, _)| name == *s))
+            {
+                return Err(format!("interface {} not found in /proc/net/dev", missing));
+            }
+            interfaces.retain(|(name, _)| selected.contains(name));
+        }
+        Ok(Snapshot {
+            time: Instant::now(),
+            interfaces,
+            snmp: Snmp::read()?,
+        })
+    }
+}
+
+impl Measurement for NetIoMeasurement {
+    fn start(&mut self) {
+        match self.snapshot() {
+            Ok(s) => self.start = Some(s),
+            Err(e) => {
+                self.start = None;
+                self.result = Err(e);
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        let start = match self.start.take() {
+            Some(s) => s,
+            None => return,
+        };
+        self.result = self.snapshot().map(|end| {
+            let secs = (end.time - start.time).as_secs_f64();
+            let per_sec = |v: u64| if secs > 0.0 { v as f64 / secs } else { 0.0 };
+
+            let mut total = InterfaceCounters::default();
+            let interfaces = end
+                .interfaces
+                .iter()
+                .map(|(name, counters)| {
+                    // an interface that appeared during the run counts from zero
+                    let counters = match start.interfaces.iter().find(|(n, _)| n == name) {
+                        Some((_, s)) => counters.delta(s),
+                        None => counters.clone(),
+                    };
+                    total.add(&counters);
+                    InterfaceIo {
+                        interface: name.clone(),
+                        rx_bandwidth: per_sec(counters.rx_bytes),
+                        tx_bandwidth: per_sec(counters.tx_bytes),
+                        counters,
+                    }
+                })
+                .collect();
+
+            NetUsage {
+                total,
+                interfaces,
+                protocols: end.snmp.protocol_io(&start.snmp),
+            }
+        });
+    }
+
+    fn result(&mut self, _ctx: &MeasureContext) -> Measure {
+        let (value, error) = match &self.result {
+            Ok(result) => (serde_json::to_value(result).unwrap(), None),
+            Err(e) => (serde_json::Value::Null, Some(e.clone())),
+        };
+
+        Measure::new("net_io", value, error)
+    }
+}
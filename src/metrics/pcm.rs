@@ -1,23 +1,65 @@
-use std::sync::{atomic::AtomicBool, Arc};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use serde::Serialize;
 use serde_json::Value;
 
-use super::{Measure, Measurement};
+use super::{Measure, MeasureContext, MeasureSample, Measurement};
+
+/// URL of the pcm-sensor-server json endpoint.
+const PCM_ENDPOINT_ENV: &str = "SHUMAI_PCM_ENDPOINT";
+/// Milliseconds between two samples.
+const PCM_INTERVAL_ENV: &str = "SHUMAI_PCM_INTERVAL_MS";
+/// Comma separated list of socket ids to report, all sockets by default.
+const PCM_SOCKETS_ENV: &str = "SHUMAI_PCM_SOCKETS";
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:9738/persecond";
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(1000);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+const UPI_IN_PREFIX: &str = "Utilization Incoming Data Traffic On Link ";
+const UPI_OUT_PREFIX: &str = "Utilization Outgoing Data And Non-Data Traffic On Link ";
 
 #[derive(Debug, Clone, Serialize)]
-pub struct PcmStats {
+pub struct UpiLinkStats {
+    link: usize,
+    in_util: f64,
+    out_util: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PcmSocketStats {
+    socket: usize,
+    pm_read: u64,
+    pm_write: u64,
+    dram_read: u64,
+    dram_write: u64,
+    l3_hit: u64,
+    l3_miss: u64,
+    /// Empty on single socket servers.
+    upi_links: Vec<UpiLinkStats>,
+}
+
+/// Sum of the selected sockets, the UPI utilizations are averaged over all of their links.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PcmTotals {
     pm_read: u64,
     pm_write: u64,
     dram_read: u64,
     dram_write: u64,
     l3_hit: u64,
     l3_miss: u64,
-    /// TODO: this is not accurate, currently it refers to the UPI link 0 on socket 0
     upi_in_util: f64,
     upi_out_util: f64,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct PcmStats {
+    sockets: Vec<PcmSocketStats>,
+    total: PcmTotals,
+}
+
 fn extract_u64(val: &Value) -> Option<u64> {
     if let Value::Number(n) = val {
         n.as_u64()
@@ -34,106 +76,336 @@ fn extract_f64(val: &Value) -> Option<f64> {
     }
 }
 
+/// Utilization of every link in a `QPI Counters Socket <n>` object, by link number.
+fn parse_upi_links(counters: &Value) -> Vec<UpiLinkStats> {
+    let counters = match counters.as_object() {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+
+    let mut links: Vec<UpiLinkStats> = Vec::new();
+    for (key, value) in counters {
+        let (link, is_in) = match (
+            key.strip_prefix(UPI_IN_PREFIX),
+            key.strip_prefix(UPI_OUT_PREFIX),
+        ) {
+            (Some(l), _) => (l, true),
+            (_, Some(l)) => (l, false),
+            _ => continue,
+        };
+        let link = match link.trim().parse::<usize>() {
+            Ok(l) => l,
+            Err(_) => continue,
+        };
+
+        let idx = match links.iter().position(|l| l.link == link) {
+            Some(idx) => idx,
+            None => {
+                links.push(UpiLinkStats {
+                    link,
+                    in_util: 0.0,
+                    out_util: 0.0,
+                });
+                links.len() - 1
+            }
+        };
+        let util = extract_f64(value).unwrap_or(0.0);
+        if is_in {
+            links[idx].in_util = util;
+        } else {
+            links[idx].out_util = util;
+        }
+    }
+    links.sort_by_key(|l| l.link);
+    links
+}
+
+impl PcmSocketStats {
+    fn from_json(socket: usize, val: &Value, upi: &Value) -> PcmSocketStats {
+        let core = &val["Core Aggregate"]["Core Counters"];
+        let uncore = &val["Uncore"]["Uncore Counters"];
+
+        PcmSocketStats {
+            socket,
+            pm_read: extract_u64(&uncore["Persistent Memory Reads"]).unwrap_or(0),
+            pm_write: extract_u64(&uncore["Persistent Memory Writes"]).unwrap_or(0),
+            dram_read: extract_u64(&uncore["DRAM Reads"]).unwrap_or(0),
+            dram_write: extract_u64(&uncore["DRAM Writes"]).unwrap_or(0),
+            l3_hit: extract_u64(&core["L3 Cache Hits"]).unwrap_or(0),
+            l3_miss: extract_u64(&core["L3 Cache Misses"]).unwrap_or(0),
+            upi_links: parse_upi_links(&upi[format!("QPI Counters Socket {}", socket)]),
+        }
+    }
+}
+
+impl PcmTotals {
+    fn sum(sockets: &[PcmSocketStats]) -> PcmTotals {
+        let mut total = PcmTotals::default();
+        let mut links = 0;
+        for s in sockets {
+            total.pm_read += s.pm_read;
+            total.pm_write += s.pm_write;
+            total.dram_read += s.dram_read;
+            total.dram_write += s.dram_write;
+            total.l3_hit += s.l3_hit;
+            total.l3_miss += s.l3_miss;
+            for l in s.upi_links.iter() {
+                total.upi_in_util += l.in_util;
+                total.upi_out_util += l.out_util;
+                links += 1;
+            }
+        }
+        if links > 0 {
+            total.upi_in_util /= links as f64;
+            total.upi_out_util /= links as f64;
+        }
+        total
+    }
+}
+
 impl PcmStats {
-    pub(crate) fn from_request() -> PcmStats {
-        let body = ureq::get("http://localhost:9738/persecond")
+    /// `sockets` selects the socket ids to keep, `None` keeps all of them.
+    pub(crate) fn from_json(val: &Value, sockets: Option<&[usize]>) -> Result<PcmStats, String> {
+        let all = val["Sockets"]
+            .as_array()
+            .ok_or("the pcm response has no `Sockets` array")?;
+        let upi = &val["QPI/UPI Links"];
+
+        let sockets: Vec<_> = all
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let id = extract_u64(&s["Socket ID"]).map_or(i, |id| id as usize);
+                (id, s)
+            })
+            .filter(|(id, _)| sockets.is_none_or(|selected| selected.contains(id)))
+            .map(|(id, s)| PcmSocketStats::from_json(id, s, upi))
+            .collect();
+
+        Ok(PcmStats {
+            total: PcmTotals::sum(&sockets),
+            sockets,
+        })
+    }
+}
+
+/// Where and how often to sample, read from the environment.
+#[derive(Clone)]
+struct PcmOptions {
+    endpoint: String,
+    interval: Duration,
+    sockets: Option<Vec<usize>>,
+}
+
+impl PcmOptions {
+    fn from_env() -> PcmOptions {
+        let interval = match std::env::var(PCM_INTERVAL_ENV) {
+            Ok(ms) => Duration::from_millis(
+                ms.parse::<u64>()
+                    .ok()
+                    .filter(|ms| *ms > 0)
+                    .expect("SHUMAI_PCM_INTERVAL_MS must be a positive number"),
+            ),
+            Err(_) => DEFAULT_INTERVAL,
+        };
+        let sockets = std::env::var(PCM_SOCKETS_ENV).ok().map(|s| {
+            s.split(',')
+                .map(|id| {
+                    id.trim()
+                        .parse::<usize>()
+                        .expect("SHUMAI_PCM_SOCKETS must be a comma separated list of socket ids")
+                })
+                .collect()
+        });
+
+        PcmOptions {
+            endpoint: std::env::var(PCM_ENDPOINT_ENV)
+                .unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string()),
+            interval,
+            sockets,
+        }
+    }
+}
+
+struct PcmClient {
+    agent: ureq::Agent,
+    options: PcmOptions,
+}
+
+impl PcmClient {
+    fn new(options: PcmOptions) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            options,
+        }
+    }
+
+    fn request(&self) -> Result<PcmStats, String> {
+        let endpoint = &self.options.endpoint;
+        let body: Value = self
+            .agent
+            .get(endpoint)
             .set("Accept", "application/json")
             .call()
-            .unwrap()
+            .map_err(|e| {
+                format!(
+                    "Failed to send request to {}, did you start the pcm-sensor-server? {}",
+                    endpoint, e
+                )
+            })?
             .into_json()
-            .expect(
-                "Failed to send request to localhost:9738, did you start the pcm-sensor-server?",
-            );
-
-        PcmStats::from_json(&body)
-    }
-
-    pub(crate) fn from_json(val: &Value) -> PcmStats {
-        let socket0 = &val["Sockets"][0];
-
-        let l3_miss = &socket0["Core Aggregate"]["Core Counters"]["L3 Cache Misses"];
-        let l3_hit = &socket0["Core Aggregate"]["Core Counters"]["L3 Cache Hits"];
-
-        let pm_read = &socket0["Uncore"]["Uncore Counters"]["Persistent Memory Reads"];
-        let pm_write = &socket0["Uncore"]["Uncore Counters"]["Persistent Memory Writes"];
-        let dram_read = &socket0["Uncore"]["Uncore Counters"]["DRAM Reads"];
-        let dram_write = &socket0["Uncore"]["Uncore Counters"]["DRAM Writes"];
-
-        let upi0 = &val["QPI/UPI Links"]["QPI Counters Socket 0"];
-        let upi_in_util = &upi0["Utilization Incoming Data Traffic On Link 0"];
-        let upi_out_util = &upi0["Utilization Outgoing Data And Non-Data Traffic On Link 0"];
-
-        let l3_miss = extract_u64(l3_miss).unwrap_or(0);
-        let l3_hit = extract_u64(l3_hit).unwrap_or(0);
-        let pm_read = extract_u64(pm_read).unwrap_or(0);
-        let pm_write = extract_u64(pm_write).unwrap_or(0);
-        let dram_read = extract_u64(dram_read).unwrap_or(0);
-        let dram_write = extract_u64(dram_write).unwrap_or(0);
-
-        // Single socket server don't have following metrics
-        let upi_in_util = extract_f64(upi_in_util).unwrap_or(0.0);
-        let upi_out_util = extract_f64(upi_out_util).unwrap_or(0.0);
-
-        PcmStats {
-            pm_read,
-            pm_write,
-            dram_read,
-            dram_write,
-            l3_hit,
-            l3_miss,
-            upi_in_util,
-            upi_out_util,
+            .map_err(|e| format!("Invalid response from {}: {}", endpoint, e))?;
+
+        PcmStats::from_json(&body, self.options.sockets.as_deref())
+    }
+}
+
+/// A sample covering `[start_ms, end_ms]` of the benchmark window.
+///
+/// pcm reports the rates of its last full second, so only full intervals are sampled:
+/// the time between the last sample and the end of the benchmark is not covered.
+#[derive(Debug, Clone)]
+struct PcmSample {
+    start_ms: u64,
+    end_ms: u64,
+    stats: PcmStats,
+}
+
+impl PcmSample {
+    fn duration_secs(&self) -> f64 {
+        (self.end_ms - self.start_ms) as f64 / 1000.0
+    }
+}
+
+/// Totals and per-second averages over the sampled window, in the units reported by pcm.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PcmSummary {
+    duration_ms: u64,
+    dram_read_avg: f64,
+    dram_write_avg: f64,
+    pm_read_avg: f64,
+    pm_write_avg: f64,
+    dram_read_total: f64,
+    dram_write_total: f64,
+    pm_read_total: f64,
+    pm_write_total: f64,
+    /// `None` if there were no L3 accesses.
+    l3_hit_ratio: Option<f64>,
+    upi_in_util_avg: f64,
+    upi_out_util_avg: f64,
+}
+
+impl PcmSummary {
+    fn from_samples(samples: &[PcmSample]) -> PcmSummary {
+        let mut summary = PcmSummary::default();
+        let (mut l3_hit, mut l3_miss) = (0.0, 0.0);
+        let mut secs = 0.0;
+        for s in samples {
+            let d = s.duration_secs();
+            let t = &s.stats.total;
+            secs += d;
+            summary.duration_ms += s.end_ms - s.start_ms;
+            summary.dram_read_total += t.dram_read as f64 * d;
+            summary.dram_write_total += t.dram_write as f64 * d;
+            summary.pm_read_total += t.pm_read as f64 * d;
+            summary.pm_write_total += t.pm_write as f64 * d;
+            l3_hit += t.l3_hit as f64 * d;
+            l3_miss += t.l3_miss as f64 * d;
+            summary.upi_in_util_avg += t.upi_in_util * d;
+            summary.upi_out_util_avg += t.upi_out_util * d;
+        }
+
+        if secs > 0.0 {
+            summary.dram_read_avg = summary.dram_read_total / secs;
+            summary.dram_write_avg = summary.dram_write_total / secs;
+            summary.pm_read_avg = summary.pm_read_total / secs;
+            summary.pm_write_avg = summary.pm_write_total / secs;
+            summary.upi_in_util_avg /= secs;
+            summary.upi_out_util_avg /= secs;
         }
+        if l3_hit + l3_miss > 0.0 {
+            summary.l3_hit_ratio = Some(l3_hit / (l3_hit + l3_miss));
+        }
+        summary
     }
 }
 
+/// Samples every interval until the benchmark stops.
+fn sample_window(client: PcmClient, stop: mpsc::Receiver<()>) -> (Vec<PcmSample>, Option<String>) {
+    let interval = client.options.interval;
+    let window_start = Instant::now();
+    let mut last = window_start;
+    let mut samples = Vec::new();
+
+    // sleeps until the next sample, unless the benchmark stops first
+    let until_next = |last: Instant| (last + interval).saturating_duration_since(Instant::now());
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(until_next(last)) {
+        let now = Instant::now();
+        match client.request() {
+            Ok(stats) => samples.push(PcmSample {
+                start_ms: (last - window_start).as_millis() as u64,
+                end_ms: (now - window_start).as_millis() as u64,
+                stats,
+            }),
+            // no point in retrying every interval if the server is gone
+            Err(e) => return (samples, Some(e)),
+        }
+        last = now;
+    }
+    (samples, None)
+}
+
 pub(crate) struct PcmMeasurement {
-    stats: Vec<PcmStats>,
-    thread_handler: Option<std::thread::JoinHandle<Vec<PcmStats>>>,
-    is_running: Arc<AtomicBool>,
+    options: PcmOptions,
+    samples: Vec<PcmSample>,
+    error: Option<String>,
+    thread_handler: Option<std::thread::JoinHandle<(Vec<PcmSample>, Option<String>)>>,
+    stop: Option<mpsc::Sender<()>>,
 }
 
 impl PcmMeasurement {
     pub(crate) fn new() -> Self {
         Self {
-            stats: vec![],
+            options: PcmOptions::from_env(),
+            samples: vec![],
+            error: None,
             thread_handler: None,
-            is_running: Arc::new(AtomicBool::new(true)),
+            stop: None,
         }
     }
 }
 
 impl Measurement for PcmMeasurement {
     fn start(&mut self) {
-        let is_running = self.is_running.clone();
-        let handler = std::thread::spawn(move || {
-            let mut stats = Vec::new();
-            let mut timer_cnt = 0;
-            while is_running.load(std::sync::atomic::Ordering::Relaxed) {
-                timer_cnt += 1;
-                if timer_cnt % 10 == 0 {
-                    stats.push(PcmStats::from_request());
-                }
-                std::thread::sleep(std::time::Duration::from_millis(100));
-            }
-            stats
-        });
-        self.thread_handler = Some(handler);
-        self.is_running
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let (stop, stopped) = mpsc::channel();
+        self.stop = Some(stop);
+        let client = PcmClient::new(self.options.clone());
+        self.thread_handler = Some(std::thread::spawn(move || sample_window(client, stopped)));
     }
 
     fn stop(&mut self) {
-        self.is_running
-            .store(false, std::sync::atomic::Ordering::Relaxed);
+        // the sampler wakes up as soon as the sender is gone
+        self.stop = None;
         let handler = self.thread_handler.take().unwrap();
-        self.stats = handler.join().unwrap();
+        (self.samples, self.error) = handler.join().unwrap();
     }
 
-    fn result(&mut self) -> Measure {
-        Measure {
-            name: "pcm".to_string(),
-            value: serde_json::to_value(self.stats.clone()).unwrap(),
-        }
+    fn result(&mut self, _ctx: &MeasureContext) -> Measure {
+        let samples = std::mem::take(&mut self.samples);
+        let summary = PcmSummary::from_samples(&samples);
+        let series = samples
+            .into_iter()
+            .map(|s| MeasureSample {
+                start_ms: s.start_ms,
+                end_ms: s.end_ms,
+                value: serde_json::to_value(s.stats).unwrap(),
+            })
+            .collect();
+        Measure::new(
+            "pcm",
+            serde_json::to_value(summary).unwrap(),
+            self.error.take(),
+        )
+        .with_series(series)
     }
 }
@@ -1,117 +1,504 @@
-use super::{Measure, Measurement};
-use perf_event::events::{Hardware, Software};
-use perf_event::{Builder, Counter};
+use super::{Measure, MeasureContext, Measurement, ThreadHook};
+use perf_event::events::{Cache, CacheId, CacheOp, CacheResult, Hardware, Raw, Software};
+use perf_event::{Builder, Counter, Group};
 use serde::Serialize;
-use std::ops::{Add, AddAssign};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 
-macro_rules! perf_builder {
-    ($(($name:ident, $event:expr)),+) => {
-        pub(crate) struct PerfStatsRaw{
-            $($name: Counter,)+
+/// Comma separated list of events to count, e.g. `cycles,inst,llc_read_miss,r01c2`.
+const PERF_EVENTS_ENV: &str = "SHUMAI_PERF_EVENTS";
+
+/// Number of events per perf group, a group is only scheduled when all of its events fit
+/// in the hardware counters, so this should not exceed the number of general purpose counters.
+const PERF_GROUP_SIZE_ENV: &str = "SHUMAI_PERF_GROUP_SIZE";
+const DEFAULT_GROUP_SIZE: usize = 4;
+
+/// Set to `1` to also count events separately for each benchmark thread.
+const PERF_PER_THREAD_ENV: &str = "SHUMAI_PERF_PER_THREAD";
+
+const DEFAULT_EVENTS: &[&str] = &[
+    "cycles",
+    "inst",
+    "branch_miss",
+    "branches",
+    "cache_reference",
+    "cache_miss",
+    "stalled_cycles_frontend",
+    "stalled_cycles_backend",
+    "bus_cycles",
+    "page_faults",
+    "context_switch",
+    "cpu_migration",
+];
+
+#[derive(Debug, Clone)]
+pub(crate) enum PerfEvent {
+    Hardware(Hardware),
+    Software(Software),
+    Cache(Cache),
+    Raw(Raw),
+}
+
+const fn cache_read(which: CacheId, result: CacheResult) -> PerfEvent {
+    PerfEvent::Cache(Cache {
+        which,
+        operation: CacheOp::READ,
+        result,
+    })
+}
+
+impl PerfEvent {
+    /// Raw PMU events are written as `r<hex>` like in `perf stat`, e.g. `r01c2`.
+    pub(crate) fn parse(name: &str) -> Option<PerfEvent> {
+        let event = match name {
+            "cycles" => PerfEvent::Hardware(Hardware::CPU_CYCLES),
+            "inst" => PerfEvent::Hardware(Hardware::INSTRUCTIONS),
+            "branch_miss" => PerfEvent::Hardware(Hardware::BRANCH_MISSES),
+            "branches" => PerfEvent::Hardware(Hardware::BRANCH_INSTRUCTIONS),
+            "cache_reference" => PerfEvent::Hardware(Hardware::CACHE_REFERENCES),
+            "cache_miss" => PerfEvent::Hardware(Hardware::CACHE_MISSES),
+            "stalled_cycles_frontend" => PerfEvent::Hardware(Hardware::STALLED_CYCLES_FRONTEND),
+            "stalled_cycles_backend" => PerfEvent::Hardware(Hardware::STALLED_CYCLES_BACKEND),
+            "bus_cycles" => PerfEvent::Hardware(Hardware::BUS_CYCLES),
+            "ref_cycles" => PerfEvent::Hardware(Hardware::REF_CPU_CYCLES),
+            "page_faults" => PerfEvent::Software(Software::PAGE_FAULTS),
+            "minor_faults" => PerfEvent::Software(Software::PAGE_FAULTS_MIN),
+            "major_faults" => PerfEvent::Software(Software::PAGE_FAULTS_MAJ),
+            "context_switch" => PerfEvent::Software(Software::CONTEXT_SWITCHES),
+            "cpu_migration" => PerfEvent::Software(Software::CPU_MIGRATIONS),
+            "task_clock" => PerfEvent::Software(Software::TASK_CLOCK),
+            "l1d_read" => cache_read(CacheId::L1D, CacheResult::ACCESS),
+            "l1d_read_miss" => cache_read(CacheId::L1D, CacheResult::MISS),
+            "llc_read" => cache_read(CacheId::LL, CacheResult::ACCESS),
+            "llc_read_miss" => cache_read(CacheId::LL, CacheResult::MISS),
+            "dtlb_read" => cache_read(CacheId::DTLB, CacheResult::ACCESS),
+            "dtlb_read_miss" => cache_read(CacheId::DTLB, CacheResult::MISS),
+            _ => {
+                let code = name.strip_prefix('r')?;
+                PerfEvent::Raw(Raw::new(u64::from_str_radix(code, 16).ok()?))
+            }
+        };
+        Some(event)
+    }
+
+    pub(crate) fn builder(&self) -> Builder<'static> {
+        match self {
+            PerfEvent::Hardware(e) => Builder::new(*e),
+            PerfEvent::Software(e) => Builder::new(*e),
+            PerfEvent::Cache(e) => Builder::new(e.clone()),
+            PerfEvent::Raw(e) => Builder::new(*e),
         }
+    }
+}
 
-        impl PerfStatsRaw {
-            pub(crate) fn new() -> PerfStatsRaw {
-                $(let $name = Builder::new($event)
-                    .inherit(true)
-                    .build()
-                    .expect(&format!("failed to create counter for {}", std::stringify!($name)));
-                )+
+fn configured_events() -> Vec<String> {
+    match std::env::var(PERF_EVENTS_ENV) {
+        Ok(events) => events
+            .split(',')
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+fn group_size() -> usize {
+    match std::env::var(PERF_GROUP_SIZE_ENV) {
+        Ok(s) => s
+            .parse::<usize>()
+            .ok()
+            .filter(|s| *s > 0)
+            .expect("SHUMAI_PERF_GROUP_SIZE must be a positive number"),
+        Err(_) => DEFAULT_GROUP_SIZE,
+    }
+}
+
+/// Counters in a group are scheduled together and read atomically.
+struct PerfGroup {
+    group: Group,
+    counters: Vec<(String, Counter)>,
+}
+
+pub(crate) struct PerfStatsRaw {
+    groups: Vec<PerfGroup>,
+    /// Events that couldn't be opened on this machine, with the reason.
+    unavailable: BTreeMap<String, String>,
+}
 
-                PerfStatsRaw{
-                    $($name,)+
+impl PerfStatsRaw {
+    /// Counts the calling thread, and the threads it spawns afterwards if `inherit` is set.
+    pub(crate) fn new(events: &[String], group_size: usize, inherit: bool) -> PerfStatsRaw {
+        let mut groups = Vec::new();
+        let mut unavailable = BTreeMap::new();
+
+        for names in events.chunks(group_size) {
+            let mut group = match Group::builder().inherit(inherit).build_group() {
+                Ok(g) => g,
+                Err(e) => {
+                    for name in names {
+                        unavailable.insert(name.clone(), e.to_string());
+                    }
+                    continue;
                 }
+            };
+
+            let mut counters = Vec::new();
+            for name in names {
+                let event = match PerfEvent::parse(name) {
+                    Some(e) => e,
+                    None => {
+                        unavailable.insert(name.clone(), "unknown event".to_string());
+                        continue;
+                    }
+                };
+                match group.add(event.builder().inherit(inherit)) {
+                    Ok(counter) => counters.push((name.clone(), counter)),
+                    Err(e) => {
+                        unavailable.insert(name.clone(), e.to_string());
+                    }
+                }
+            }
+
+            if !counters.is_empty() {
+                groups.push(PerfGroup { group, counters });
             }
         }
 
-        #[derive(Debug, Clone, Serialize)]
-        pub struct PerfCounter {
-            $(pub $name: u64,)+
+        PerfStatsRaw {
+            groups,
+            unavailable,
         }
+    }
 
-        impl AddAssign for PerfCounter {
-            fn add_assign(&mut self, other: Self) {
-                *self = Self {
-                    $($name: self.$name + other.$name,)+
-                }
+    pub(crate) fn get_stats(&mut self) -> std::io::Result<PerfStats> {
+        let mut counters = BTreeMap::new();
+        let mut groups = Vec::new();
+        for g in self.groups.iter_mut() {
+            let data = g.group.read()?;
+            let time_enabled = data.time_enabled().unwrap_or_default().as_nanos() as u64;
+            let time_running = data.time_running().unwrap_or_default().as_nanos() as u64;
+
+            let mut raw = BTreeMap::new();
+            for (name, counter) in g.counters.iter() {
+                let value = data[counter];
+                raw.insert(name.clone(), value);
+                counters.insert(name.clone(), scale(value, time_enabled, time_running));
             }
+            groups.push(PerfGroupStats {
+                raw,
+                time_enabled_ns: time_enabled,
+                time_running_ns: time_running,
+            });
         }
 
-        impl Add for PerfCounter {
-            type Output = PerfCounter;
-            fn add(self, other: PerfCounter)->PerfCounter{
-                Self {
-                    $($name: self.$name + other.$name,)+
-                }
-            }
+        let multiplexed = groups.iter().any(|g| g.time_running_ns < g.time_enabled_ns);
+
+        Ok(PerfStats {
+            counters,
+            derived: PerfDerived::default(),
+            per_op: BTreeMap::new(),
+            groups,
+            multiplexed,
+            unavailable: self.unavailable.clone(),
+        })
+    }
+
+    /// Resets the counters so that each iteration counts from zero.
+    pub(crate) fn reset(&mut self) -> std::io::Result<()> {
+        for g in self.groups.iter_mut() {
+            g.group.reset()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn enable(&mut self) -> std::io::Result<()> {
+        for g in self.groups.iter_mut() {
+            g.group.enable()?;
         }
+        Ok(())
+    }
 
-        impl PerfStatsRaw {
-            pub(crate) fn get_stats(&mut self) -> std::io::Result<PerfCounter> {
-                let stats = PerfCounter {
-                    $($name: self.$name.read()?, )+
-                };
+    pub(crate) fn disable(&mut self) -> std::io::Result<()> {
+        for g in self.groups.iter_mut() {
+            g.group.disable()?;
+        }
+        Ok(())
+    }
+}
 
-                Ok(stats)
-            }
+/// Estimates the full count of a counter that was only scheduled for part of the time it was enabled.
+fn scale(value: u64, time_enabled: u64, time_running: u64) -> u64 {
+    if time_running == 0 || time_running >= time_enabled {
+        return value;
+    }
+    (value as u128 * time_enabled as u128 / time_running as u128) as u64
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PerfStats {
+    /// Counter values, scaled by `time_enabled / time_running` if the counters were multiplexed.
+    pub counters: BTreeMap<String, u64>,
+    pub derived: PerfDerived,
+    /// Every counter divided by the number of operations of the iteration.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub per_op: BTreeMap<String, f64>,
+    pub groups: Vec<PerfGroupStats>,
+    /// Whether any group was not counting for the whole time it was enabled.
+    pub multiplexed: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unavailable: BTreeMap<String, String>,
+}
 
-            pub(crate) fn enable(&mut self) -> std::io::Result<()> {
-                $(self.$name.enable()?;)+
-                Ok(())
+/// The unscaled values of a group, along with how long it was enabled and actually counting.
+#[derive(Debug, Clone, Serialize)]
+pub struct PerfGroupStats {
+    pub raw: BTreeMap<String, u64>,
+    pub time_enabled_ns: u64,
+    pub time_running_ns: u64,
+}
+
+/// Ratios between counters, `None` if a counter they depend on is unavailable.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PerfDerived {
+    pub ipc: Option<f64>,
+    pub branch_miss_rate: Option<f64>,
+    pub cache_miss_rate: Option<f64>,
+}
+
+impl PerfDerived {
+    fn new(counters: &BTreeMap<String, u64>) -> PerfDerived {
+        PerfDerived {
+            ipc: ratio(counters, "inst", "cycles"),
+            branch_miss_rate: ratio(counters, "branch_miss", "branches"),
+            cache_miss_rate: ratio(counters, "cache_miss", "cache_reference"),
+        }
+    }
+}
+
+fn ratio(counters: &BTreeMap<String, u64>, numerator: &str, denominator: &str) -> Option<f64> {
+    let numerator = *counters.get(numerator)?;
+    let denominator = *counters.get(denominator)?;
+    if denominator == 0 {
+        return None;
+    }
+    Some(numerator as f64 / denominator as f64)
+}
+
+impl PerfStats {
+    /// Sums the counters of several threads, the groups are not kept as their times differ per thread.
+    fn total(stats: &[PerfThreadStats]) -> PerfStats {
+        let mut counters = BTreeMap::new();
+        let mut unavailable = BTreeMap::new();
+        for s in stats {
+            for (name, v) in s.stats.counters.iter() {
+                *counters.entry(name.clone()).or_insert(0) += *v;
             }
+            unavailable.extend(s.stats.unavailable.clone());
+        }
+        PerfStats {
+            counters,
+            derived: PerfDerived::default(),
+            per_op: BTreeMap::new(),
+            groups: Vec::new(),
+            multiplexed: stats.iter().any(|s| s.stats.multiplexed),
+            unavailable,
+        }
+    }
 
-            pub(crate) fn disable(&mut self) -> std::io::Result<()> {
-                $(self.$name.disable()?;)+
-                Ok(())
+    fn warn_multiplexed(&self, label: &str) {
+        for g in self.groups.iter() {
+            if g.time_running_ns < g.time_enabled_ns {
+                eprintln!(
+                    "Warning: perf counters {:?}{} were multiplexed, only counted {:.1}% of the time, values are scaled estimates",
+                    g.raw.keys().collect::<Vec<_>>(),
+                    label,
+                    g.time_running_ns as f64 * 100.0 / g.time_enabled_ns as f64
+                );
             }
         }
-    };
+    }
+
+    fn derive(&mut self, ops: usize) {
+        self.derived = PerfDerived::new(&self.counters);
+
+        if ops > 0 {
+            self.per_op = self
+                .counters
+                .iter()
+                .map(|(name, v)| (name.clone(), *v as f64 / ops as f64))
+                .collect();
+        }
+    }
 }
 
-perf_builder!(
-    (cycles, Hardware::CPU_CYCLES),
-    (inst, Hardware::INSTRUCTIONS),
-    (branch_miss, Hardware::BRANCH_MISSES),
-    (branches, Hardware::BRANCH_INSTRUCTIONS),
-    (cache_reference, Hardware::CACHE_REFERENCES),
-    (cache_miss, Hardware::CACHE_MISSES),
-    (stalled_cycles_frontend, Hardware::STALLED_CYCLES_FRONTEND),
-    // (stalled_cycles_backend, Hardware::STALLED_CYCLES_BACKEND), // it seems AMD cpu doesn't support this
-    // (bus_cycles, Hardware::BUS_CYCLES), // it seems AMD cpu doesn't support this
-    (page_faults, Software::PAGE_FAULTS),
-    (context_switch, Software::CONTEXT_SWITCHES),
-    (cpu_migration, Software::CPU_MIGRATIONS)
-);
+/// Counters of a single benchmark thread, `tid` is the thread id from the benchmark `Context`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PerfThreadStats {
+    pub tid: usize,
+    #[serde(flatten)]
+    pub stats: PerfStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PerfThreadReport {
+    pub per_thread: Vec<PerfThreadStats>,
+    /// Sum of the per-thread counters.
+    pub total: PerfStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PerfReport {
+    #[serde(flatten)]
+    process: PerfStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threads: Option<PerfThreadReport>,
+}
+
+/// Per-thread counters, opened by each benchmark thread for itself before it starts running,
+/// they are enabled together with the process-wide counters.
+pub(crate) struct ThreadCounters {
+    events: Vec<String>,
+    group_size: usize,
+    counters: Mutex<Vec<(usize, PerfStatsRaw)>>,
+}
+
+impl ThreadCounters {
+    /// Returns `None` unless `SHUMAI_PERF_PER_THREAD` is set.
+    pub(crate) fn from_env() -> Option<Arc<ThreadCounters>> {
+        match std::env::var(PERF_PER_THREAD_ENV) {
+            Ok(v) if v == "1" || v.eq_ignore_ascii_case("true") => Some(Arc::new(ThreadCounters {
+                events: configured_events(),
+                group_size: group_size(),
+                counters: Mutex::new(Vec::new()),
+            })),
+            _ => None,
+        }
+    }
+
+    fn for_each(
+        &self,
+        f: impl Fn(&mut PerfStatsRaw) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        for (_, stats) in self.counters.lock().unwrap().iter_mut() {
+            f(stats)?;
+        }
+        Ok(())
+    }
+
+    /// Reads and drops the counters of the finished iteration, the next one spawns new threads.
+    fn take_stats(&self, ctx: &MeasureContext) -> std::io::Result<PerfThreadReport> {
+        let mut counters = std::mem::take(&mut *self.counters.lock().unwrap());
+        counters.sort_by_key(|(tid, _)| *tid);
+
+        let mut per_thread = Vec::new();
+        for (tid, stats) in counters.iter_mut() {
+            let mut stats = stats.get_stats()?;
+            stats.derive(ctx.thread_ops.get(*tid).copied().unwrap_or(0));
+            stats.warn_multiplexed(&format!(" of thread {}", tid));
+            per_thread.push(PerfThreadStats { tid: *tid, stats });
+        }
+
+        let mut total = PerfStats::total(&per_thread);
+        total.derive(ctx.ops);
+        Ok(PerfThreadReport { per_thread, total })
+    }
+}
+
+impl ThreadHook for ThreadCounters {
+    fn on_thread_start(&self, tid: usize) {
+        let stats = PerfStatsRaw::new(&self.events, self.group_size, false);
+        self.counters.lock().unwrap().push((tid, stats));
+    }
+}
+
+/// The process counters since the previous sample.
+#[derive(Debug, Clone, Serialize)]
+pub struct PerfSample {
+    pub counters: BTreeMap<String, u64>,
+    pub derived: PerfDerived,
+}
 
 pub(crate) struct PerfMeasurement {
     stats: PerfStatsRaw,
+    threads: Option<Arc<ThreadCounters>>,
+    /// The counters at the previous sample.
+    sampled: BTreeMap<String, u64>,
 }
 
 impl PerfMeasurement {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(threads: Option<Arc<ThreadCounters>>) -> Self {
         Self {
-            stats: PerfStatsRaw::new(),
+            stats: PerfStatsRaw::new(&configured_events(), group_size(), true),
+            threads,
+            sampled: BTreeMap::new(),
         }
     }
 }
 
 impl Measurement for PerfMeasurement {
     fn start(&mut self) {
+        self.sampled.clear();
+        self.stats.reset().expect("unable to reset perf counters");
         self.stats.enable().expect("unable to enable perf counters");
+        if let Some(threads) = &self.threads {
+            threads
+                .for_each(|s| {
+                    s.reset()?;
+                    s.enable()
+                })
+                .expect("unable to enable per-thread perf counters");
+        }
     }
 
     fn stop(&mut self) {
         self.stats
             .disable()
             .expect("unable to disable perf counters");
+        if let Some(threads) = &self.threads {
+            threads
+                .for_each(|s| s.disable())
+                .expect("unable to disable per-thread perf counters");
+        }
+    }
+
+    fn sample(&mut self) -> Option<serde_json::Value> {
+        let stats = self.stats.get_stats().ok()?;
+        let counters: BTreeMap<String, u64> = stats
+            .counters
+            .iter()
+            .map(|(name, v)| {
+                let last = self.sampled.get(name).copied().unwrap_or(0);
+                (name.clone(), v.saturating_sub(last))
+            })
+            .collect();
+        self.sampled = stats.counters;
+        Some(
+            serde_json::to_value(PerfSample {
+                derived: PerfDerived::new(&counters),
+                counters,
+            })
+            .unwrap(),
+        )
     }
 
-    fn result(&mut self) -> Measure {
-        let stats = self.stats.get_stats().expect("unable to get perf counters");
+    fn result(&mut self, ctx: &MeasureContext) -> Measure {
+        let mut stats = self.stats.get_stats().expect("unable to get perf counters");
+        stats.derive(ctx.ops);
+        stats.warn_multiplexed("");
 
-        Measure {
-            name: "perf".to_string(),
-            value: serde_json::to_value(stats).unwrap(),
-        }
+        let threads = self.threads.as_ref().map(|t| {
+            t.take_stats(ctx)
+                .expect("unable to get per-thread perf counters")
+        });
+
+        Measure::new(
+            "perf",
+            serde_json::to_value(PerfReport {
+                process: stats,
+                threads,
+            })
+            .unwrap(),
+            None,
+        )
     }
 }
@@ -0,0 +1,243 @@
+use super::perf::PerfEvent;
+use super::{Measure, MeasureContext, Measurement, ThreadHook};
+use perf_event::data::Record;
+use perf_event::{SampleFlag, Sampler};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The event to sample, e.g. `cycles`, `cache_miss` or `llc_read_miss`; sampling is off unless it is set.
+const PERF_SAMPLE_ENV: &str = "SHUMAI_PERF_SAMPLE";
+/// Samples per second and thread, the kernel adjusts the sample period to reach it.
+const PERF_SAMPLE_FREQ_ENV: &str = "SHUMAI_PERF_SAMPLE_FREQ";
+/// Number of functions in the result table.
+const PERF_SAMPLE_TOP_ENV: &str = "SHUMAI_PERF_SAMPLE_TOP";
+
+const DEFAULT_FREQ: u64 = 1000;
+const DEFAULT_TOP: usize = 20;
+
+/// Ring buffer size of each thread, it is drained every `DRAIN_INTERVAL` while the benchmark runs.
+const BUFFER_BYTES: usize = 512 * 1024;
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(s) => s
+            .parse::<T>()
+            .unwrap_or_else(|_| panic!("{} must be a number", name)),
+        Err(_) => default,
+    }
+}
+
+#[derive(Default)]
+struct SampleCounts {
+    /// Samples and summed sample periods (the estimated event count) by instruction pointer.
+    by_ip: HashMap<u64, (u64, u64)>,
+    lost: u64,
+}
+
+/// Samplers of the benchmark threads, each thread opens its own as inherited events can't be mmapped.
+pub(crate) struct Samplers {
+    event_name: String,
+    event: Option<PerfEvent>,
+    freq: u64,
+    samplers: Mutex<Vec<Sampler>>,
+    /// Why a thread couldn't open its sampler, the last error wins.
+    error: Mutex<Option<String>>,
+    counts: Mutex<SampleCounts>,
+}
+
+impl Samplers {
+    /// Returns `None` unless `SHUMAI_PERF_SAMPLE` is set.
+    pub(crate) fn from_env() -> Option<Arc<Samplers>> {
+        let event_name = std::env::var(PERF_SAMPLE_ENV).ok()?;
+        Some(Arc::new(Samplers {
+            event: PerfEvent::parse(&event_name),
+            event_name,
+            freq: env_number(PERF_SAMPLE_FREQ_ENV, DEFAULT_FREQ),
+            samplers: Mutex::new(Vec::new()),
+            error: Mutex::new(None),
+            counts: Mutex::new(SampleCounts::default()),
+        }))
+    }
+
+    fn open(&self) -> Result<Sampler, String> {
+        let event = self.event.as_ref().ok_or("unknown event")?;
+        event
+            .builder()
+            .sample_frequency(self.freq)
+            .sample(SampleFlag::IP | SampleFlag::PERIOD)
+            .enabled(false)
+            .build()
+            .and_then(|c| c.sampled(BUFFER_BYTES))
+            .map_err(|e| e.to_string())
+    }
+
+    fn for_each(&self, f: impl Fn(&mut Sampler) -> std::io::Result<()>) -> std::io::Result<()> {
+        for s in self.samplers.lock().unwrap().iter_mut() {
+            f(s)?;
+        }
+        Ok(())
+    }
+
+    fn drain(&self) {
+        let mut samplers = self.samplers.lock().unwrap();
+        let mut counts = self.counts.lock().unwrap();
+        for s in samplers.iter_mut() {
+            while let Some(record) = s.next_record() {
+                match record.parse_record() {
+                    Ok(Record::Sample(sample)) => {
+                        if let Some(ip) = sample.ip() {
+                            let entry = counts.by_ip.entry(ip).or_default();
+                            entry.0 += 1;
+                            entry.1 += sample.period().unwrap_or(1);
+                        }
+                    }
+                    Ok(Record::Lost(lost)) => counts.lost += lost.lost,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl ThreadHook for Samplers {
+    fn on_thread_start(&self, _tid: usize) {
+        match self.open() {
+            Ok(s) => self.samplers.lock().unwrap().push(s),
+            Err(e) => *self.error.lock().unwrap() = Some(e),
+        }
+    }
+}
+
+/// The function containing `ip`, inlined frames are attributed to the function they are inlined into.
+fn symbolize(ip: u64) -> String {
+    let mut name = None;
+    backtrace::resolve(ip as usize as *mut std::ffi::c_void, |symbol| {
+        if let Some(n) = symbol.name() {
+            name = Some(format!("{:#}", n));
+        }
+    });
+    name.unwrap_or_else(|| format!("{:#x}", ip))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HotFunction {
+    pub function: String,
+    pub samples: u64,
+    /// Estimated number of events, the sum of the sample periods.
+    pub events: u64,
+    pub percent: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PerfSampleStats {
+    pub event: String,
+    pub samples: u64,
+    pub events: u64,
+    /// Samples dropped because a ring buffer was full.
+    pub lost: u64,
+    pub top: Vec<HotFunction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unavailable: Option<String>,
+}
+
+pub(crate) struct PerfSampleMeasurement {
+    samplers: Arc<Samplers>,
+    top: usize,
+    drain_stop: Arc<AtomicBool>,
+    drain_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl PerfSampleMeasurement {
+    pub(crate) fn new(samplers: Arc<Samplers>) -> Self {
+        Self {
+            samplers,
+            top: env_number(PERF_SAMPLE_TOP_ENV, DEFAULT_TOP),
+            drain_stop: Arc::new(AtomicBool::new(false)),
+            drain_thread: None,
+        }
+    }
+
+    fn stats(&self, counts: SampleCounts) -> PerfSampleStats {
+        let mut by_function: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for (ip, (samples, events)) in counts.by_ip {
+            let entry = by_function.entry(symbolize(ip)).or_default();
+            entry.0 += samples;
+            entry.1 += events;
+        }
+
+        let samples = by_function.values().map(|v| v.0).sum();
+        let events: u64 = by_function.values().map(|v| v.1).sum();
+        let mut top: Vec<_> = by_function
+            .into_iter()
+            .map(|(function, (samples, e))| HotFunction {
+                function,
+                samples,
+                events: e,
+                percent: if events == 0 {
+                    0.0
+                } else {
+                    e as f64 * 100.0 / events as f64
+                },
+            })
+            .collect();
+        top.sort_by_key(|f| std::cmp::Reverse(f.events));
+        top.truncate(self.top);
+
+        PerfSampleStats {
+            event: self.samplers.event_name.clone(),
+            samples,
+            events,
+            lost: counts.lost,
+            top,
+            unavailable: self.samplers.error.lock().unwrap().take(),
+        }
+    }
+}
+
+impl Measurement for PerfSampleMeasurement {
+    fn start(&mut self) {
+        self.samplers
+            .for_each(|s| {
+                s.reset()?;
+                s.enable()
+            })
+            .expect("unable to enable perf samplers");
+
+        self.drain_stop.store(false, Ordering::SeqCst);
+        let samplers = self.samplers.clone();
+        let stop = self.drain_stop.clone();
+        self.drain_thread = Some(std::thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                samplers.drain();
+                std::thread::sleep(DRAIN_INTERVAL);
+            }
+        }));
+    }
+
+    fn stop(&mut self) {
+        self.samplers
+            .for_each(|s| s.disable())
+            .expect("unable to disable perf samplers");
+        self.drain_stop.store(true, Ordering::SeqCst);
+        if let Some(t) = self.drain_thread.take() {
+            t.join().unwrap();
+        }
+        self.samplers.drain();
+    }
+
+    fn result(&mut self, _ctx: &MeasureContext) -> Measure {
+        // the benchmark threads are gone, the next iteration opens new samplers
+        self.samplers.samplers.lock().unwrap().clear();
+        let counts = std::mem::take(&mut *self.samplers.counts.lock().unwrap());
+
+        Measure::new(
+            "perf_sample",
+            serde_json::to_value(self.stats(counts)).unwrap(),
+            None,
+        )
+    }
+}
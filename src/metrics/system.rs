@@ -0,0 +1,482 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::{read_stat_fields, Measure, MeasureContext, MeasureSample, Measurement};
+
+/// Milliseconds between two samples of the system counters.
+const SYSTEM_INTERVAL_ENV: &str = "SHUMAI_SYSTEM_INTERVAL_MS";
+/// Fraction of the machine's CPU time used outside the benchmark process above which an iteration is flagged.
+const NOISE_THRESHOLD_ENV: &str = "SHUMAI_NOISE_THRESHOLD";
+/// Thermal throttle events above which an iteration is flagged.
+const THROTTLE_THRESHOLD_ENV: &str = "SHUMAI_THROTTLE_THRESHOLD";
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(200);
+const DEFAULT_NOISE_THRESHOLD: f64 = 0.05;
+
+const CPU_SYSFS: &str = "/sys/devices/system/cpu";
+
+/// Frequency of a core over the samples, in MHz.
+#[derive(Debug, Clone, Serialize)]
+pub struct FrequencyStats {
+    pub avg: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreStats {
+    pub cpu: usize,
+    /// Fraction of the window the core was busy, whatever it ran.
+    pub utilization: f64,
+    pub iowait: f64,
+    pub interrupts: u64,
+    /// `None` if the core has no `cpufreq`, e.g. in most VMs.
+    pub frequency_mhz: Option<FrequencyStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThrottleStats {
+    pub core_throttle_count: u64,
+    pub package_throttle_count: u64,
+}
+
+/// What changed between two snapshots, the value of a `series` sample.
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemSample {
+    pub utilization: f64,
+    pub noise: f64,
+    /// Average over the cores with `cpufreq`.
+    pub frequency_mhz: Option<f64>,
+    pub interrupts: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemStats {
+    /// Busy fraction of all cores over the window.
+    pub utilization: f64,
+    /// CPU time of the benchmark process as a fraction of all cores over the window.
+    pub benchmark_utilization: f64,
+    /// CPU time used outside the benchmark process as a fraction of all cores over the window.
+    pub noise: f64,
+    pub interrupts: u64,
+    pub softirqs: u64,
+    pub context_switches: u64,
+    /// `None` if the kernel does not expose `thermal_throttle`.
+    pub throttle: Option<ThrottleStats>,
+    pub noisy: bool,
+    pub throttled: bool,
+    pub cpus: Vec<CoreStats>,
+}
+
+/// Jiffies of a core from a `cpuN` line of `/proc/stat`.
+#[derive(Debug, Clone, Default)]
+struct CpuTimes {
+    busy: u64,
+    iowait: u64,
+    total: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+    time: Instant,
+    all: CpuTimes,
+    cpus: BTreeMap<usize, CpuTimes>,
+    process_ticks: u64,
+    interrupts: u64,
+    softirqs: u64,
+    context_switches: u64,
+    core_interrupts: BTreeMap<usize, u64>,
+    freq_khz: BTreeMap<usize, u64>,
+    throttle: Option<(u64, u64)>,
+}
+
+fn parse_cpu_times(values: &[&str]) -> Result<CpuTimes, String> {
+    let values: Vec<u64> = values
+        .iter()
+        .map(|v| v.parse::<u64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid cpu line in /proc/stat: {}", e))?;
+    if values.len() < 8 {
+        return Err("invalid cpu line in /proc/stat".to_string());
+    }
+    // user nice system idle iowait irq softirq steal, guest time is already part of user
+    let busy = values[0] + values[1] + values[2] + values[5] + values[6] + values[7];
+    Ok(CpuTimes {
+        busy,
+        iowait: values[4],
+        total: busy + values[3] + values[4],
+    })
+}
+
+/// Sums the columns of `/proc/interrupts` by CPU, lines like `ERR` with fewer columns are skipped.
+fn read_core_interrupts() -> BTreeMap<usize, u64> {
+    let content = match std::fs::read_to_string("/proc/interrupts") {
+        Ok(c) => c,
+        Err(_) => return BTreeMap::new(),
+    };
+    let mut lines = content.lines();
+    let cpus: Vec<usize> = match lines.next() {
+        Some(header) => header
+            .split_whitespace()
+            .filter_map(|c| c.strip_prefix("CPU")?.parse().ok())
+            .collect(),
+        None => return BTreeMap::new(),
+    };
+
+    let mut counts: BTreeMap<usize, u64> = cpus.iter().map(|c| (*c, 0)).collect();
+    for line in lines {
+        let columns: Vec<u64> = line
+            .split_whitespace()
+            .skip(1)
+            .take(cpus.len())
+            .map_while(|v| v.parse().ok())
+            .collect();
+        if columns.len() == cpus.len() {
+            for (cpu, count) in cpus.iter().zip(columns) {
+                *counts.get_mut(cpu).unwrap() += count;
+            }
+        }
+    }
+    counts
+}
+
+fn read_sysfs_u64(path: &str) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+impl Snapshot {
+    fn read() -> Result<Snapshot, String> {
+        let content = std::fs::read_to_string("/proc/stat")
+            .map_err(|e| format!("unable to read /proc/stat: {}", e))?;
+        let total = |values: &[&str]| -> Result<u64, String> {
+            values
+                .first()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| format!("invalid `{}` in /proc/stat", values.join(" ")))
+        };
+
+        let mut all = None;
+        let mut cpus = BTreeMap::new();
+        let (mut interrupts, mut softirqs, mut context_switches) = (0, 0, 0);
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            let key = fields.next().unwrap_or_default();
+            let values: Vec<&str> = fields.collect();
+            match key {
+                "cpu" => all = Some(parse_cpu_times(&values)?),
+                "intr" => interrupts = total(&values)?,
+                "softirq" => softirqs = total(&values)?,
+                "ctxt" => context_switches = total(&values)?,
+                _ => {
+                    if let Some(cpu) = key.strip_prefix("cpu").and_then(|c| c.parse().ok()) {
+                        cpus.insert(cpu, parse_cpu_times(&values)?);
+                    }
+                }
+            }
+        }
+        let all = all.ok_or("no cpu line in /proc/stat")?;
+
+        let utime_stime = read_stat_fields("/proc/self/stat", &[14, 15])?;
+
+        let freq_khz = cpus
+            .keys()
+            .filter_map(|cpu| {
+                let path = format!("{}/cpu{}/cpufreq/scaling_cur_freq", CPU_SYSFS, cpu);
+                Some((*cpu, read_sysfs_u64(&path)?))
+            })
+            .collect();
+
+        // every core of a package repeats the package counter, so it is only counted once per package
+        let mut core_throttle = None;
+        let mut package_throttle = BTreeMap::new();
+        for cpu in cpus.keys() {
+            let dir = format!("{}/cpu{}/thermal_throttle", CPU_SYSFS, cpu);
+            if let Some(count) = read_sysfs_u64(&format!("{}/core_throttle_count", dir)) {
+                *core_throttle.get_or_insert(0) += count;
+            }
+            if let Some(count) = read_sysfs_u64(&format!("{}/package_throttle_count", dir)) {
+                let package = format!("{}/cpu{}/topology/physical_package_id", CPU_SYSFS, cpu);
+                package_throttle.insert(read_sysfs_u64(&package).unwrap_or(0), count);
+            }
+        }
+        let throttle = match (core_throttle, package_throttle.is_empty()) {
+            (None, true) => None,
+            (core, _) => Some((core.unwrap_or(0), package_throttle.values().sum())),
+        };
+
+        Ok(Snapshot {
+            time: Instant::now(),
+            all,
+            cpus,
+            process_ticks: utime_stime[0] + utime_stime[1],
+            interrupts,
+            softirqs,
+            context_switches,
+            core_interrupts: read_core_interrupts(),
+            freq_khz,
+            throttle,
+        })
+    }
+}
+
+/// CPU usage between two snapshots, as fractions of all cores: `(utilization, benchmark, noise)`.
+fn usage(start: &Snapshot, end: &Snapshot) -> (f64, f64, f64) {
+    let total = end.all.total.saturating_sub(start.all.total);
+    if total == 0 {
+        return (0.0, 0.0, 0.0);
+    }
+    let busy = end.all.busy.saturating_sub(start.all.busy);
+    // both are in USER_HZ ticks
+    let process = end.process_ticks.saturating_sub(start.process_ticks);
+    (
+        busy as f64 / total as f64,
+        process as f64 / total as f64,
+        busy.saturating_sub(process) as f64 / total as f64,
+    )
+}
+
+fn avg_freq_mhz(freq_khz: &BTreeMap<usize, u64>) -> Option<f64> {
+    if freq_khz.is_empty() {
+        return None;
+    }
+    Some(freq_khz.values().sum::<u64>() as f64 / freq_khz.len() as f64 / 1000.0)
+}
+
+/// Where and how often to sample, and when to flag an iteration, read from the environment.
+#[derive(Clone)]
+struct SystemOptions {
+    interval: Duration,
+    noise_threshold: f64,
+    throttle_threshold: u64,
+}
+
+impl SystemOptions {
+    fn from_env() -> SystemOptions {
+        let interval = match std::env::var(SYSTEM_INTERVAL_ENV) {
+            Ok(ms) => Duration::from_millis(
+                ms.parse::<u64>()
+                    .ok()
+                    .filter(|ms| *ms > 0)
+                    .expect("SHUMAI_SYSTEM_INTERVAL_MS must be a positive number"),
+            ),
+            Err(_) => DEFAULT_INTERVAL,
+        };
+        let noise_threshold = match std::env::var(NOISE_THRESHOLD_ENV) {
+            Ok(t) => t
+                .parse::<f64>()
+                .ok()
+                .filter(|t| *t >= 0.0)
+                .expect("SHUMAI_NOISE_THRESHOLD must be a non-negative fraction, e.g. 0.05"),
+            Err(_) => DEFAULT_NOISE_THRESHOLD,
+        };
+        let throttle_threshold = match std::env::var(THROTTLE_THRESHOLD_ENV) {
+            Ok(t) => t
+                .parse::<u64>()
+                .expect("SHUMAI_THROTTLE_THRESHOLD must be a number of throttle events"),
+            Err(_) => 0,
+        };
+
+        SystemOptions {
+            interval,
+            noise_threshold,
+            throttle_threshold,
+        }
+    }
+}
+
+/// Takes a snapshot every interval until `stop` is signalled or dropped, and a last one when the benchmark stops.
+fn sample_window(
+    first: Snapshot,
+    interval: Duration,
+    stop: mpsc::Receiver<()>,
+) -> (Vec<Snapshot>, Option<String>) {
+    let mut snapshots = vec![first];
+
+    loop {
+        let until_next =
+            (snapshots.last().unwrap().time + interval).saturating_duration_since(Instant::now());
+        // sleeps until the next sample, unless the benchmark stops first
+        let running = matches!(
+            stop.recv_timeout(until_next),
+            Err(RecvTimeoutError::Timeout)
+        );
+        match Snapshot::read() {
+            Ok(s) => snapshots.push(s),
+            Err(e) => return (snapshots, Some(e)),
+        }
+        if !running {
+            return (snapshots, None);
+        }
+    }
+}
+
+/// A sample for every pair of consecutive snapshots, relative to the first one.
+fn series(snapshots: &[Snapshot]) -> Vec<MeasureSample> {
+    let window_start = snapshots[0].time;
+    snapshots
+        .windows(2)
+        .map(|w| {
+            let (utilization, _, noise) = usage(&w[0], &w[1]);
+            let sample = SystemSample {
+                utilization,
+                noise,
+                frequency_mhz: avg_freq_mhz(&w[1].freq_khz),
+                interrupts: w[1].interrupts.saturating_sub(w[0].interrupts),
+            };
+            MeasureSample {
+                start_ms: (w[0].time - window_start).as_millis() as u64,
+                end_ms: (w[1].time - window_start).as_millis() as u64,
+                value: serde_json::to_value(sample).unwrap(),
+            }
+        })
+        .collect()
+}
+
+fn summarize(snapshots: &[Snapshot], options: &SystemOptions) -> SystemStats {
+    let (first, last) = (&snapshots[0], snapshots.last().unwrap());
+
+    let cpus = last
+        .cpus
+        .iter()
+        .map(|(cpu, end)| {
+            let start = first.cpus.get(cpu).cloned().unwrap_or_default();
+            let total = end.total.saturating_sub(start.total);
+            let fraction = |v: u64| {
+                if total > 0 {
+                    v as f64 / total as f64
+                } else {
+                    0.0
+                }
+            };
+            let freqs: Vec<f64> = snapshots[1..]
+                .iter()
+                .filter_map(|s| s.freq_khz.get(cpu))
+                .map(|khz| *khz as f64 / 1000.0)
+                .collect();
+            CoreStats {
+                cpu: *cpu,
+                utilization: fraction(end.busy.saturating_sub(start.busy)),
+                iowait: fraction(end.iowait.saturating_sub(start.iowait)),
+                interrupts: last
+                    .core_interrupts
+                    .get(cpu)
+                    .unwrap_or(&0)
+                    .saturating_sub(*first.core_interrupts.get(cpu).unwrap_or(&0)),
+                frequency_mhz: (!freqs.is_empty()).then(|| FrequencyStats {
+                    avg: freqs.iter().sum::<f64>() / freqs.len() as f64,
+                    min: freqs.iter().copied().fold(f64::INFINITY, f64::min),
+                    max: freqs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                }),
+            }
+        })
+        .collect();
+
+    let throttle = match (first.throttle, last.throttle) {
+        (Some((c0, p0)), Some((c1, p1))) => Some(ThrottleStats {
+            core_throttle_count: c1.saturating_sub(c0),
+            package_throttle_count: p1.saturating_sub(p0),
+        }),
+        _ => None,
+    };
+    let (utilization, benchmark_utilization, noise) = usage(first, last);
+
+    SystemStats {
+        utilization,
+        benchmark_utilization,
+        noise,
+        interrupts: last.interrupts.saturating_sub(first.interrupts),
+        softirqs: last.softirqs.saturating_sub(first.softirqs),
+        context_switches: last.context_switches.saturating_sub(first.context_switches),
+        noisy: noise > options.noise_threshold,
+        throttled: throttle.as_ref().is_some_and(|t| {
+            t.core_throttle_count + t.package_throttle_count > options.throttle_threshold
+        }),
+        throttle,
+        cpus,
+    }
+}
+
+pub(crate) struct SystemMeasurement {
+    options: SystemOptions,
+    snapshots: Vec<Snapshot>,
+    error: Option<String>,
+    thread_handler: Option<std::thread::JoinHandle<(Vec<Snapshot>, Option<String>)>>,
+    stop: Option<mpsc::Sender<()>>,
+}
+
+impl SystemMeasurement {
+    pub(crate) fn new() -> Self {
+        Self {
+            options: SystemOptions::from_env(),
+            snapshots: vec![],
+            error: None,
+            thread_handler: None,
+            stop: None,
+        }
+    }
+}
+
+impl Measurement for SystemMeasurement {
+    fn start(&mut self) {
+        let first = match Snapshot::read() {
+            Ok(s) => s,
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            }
+        };
+        let (stop, stopped) = mpsc::channel();
+        self.stop = Some(stop);
+        let interval = self.options.interval;
+        self.thread_handler = Some(std::thread::spawn(move || {
+            sample_window(first, interval, stopped)
+        }));
+    }
+
+    fn stop(&mut self) {
+        // the sampler wakes up as soon as the sender is gone
+        self.stop = None;
+        if let Some(handler) = self.thread_handler.take() {
+            (self.snapshots, self.error) = handler.join().unwrap();
+        }
+    }
+
+    fn result(&mut self, ctx: &MeasureContext) -> Measure {
+        let snapshots = std::mem::take(&mut self.snapshots);
+        let error = self.error.take();
+        if snapshots.len() < 2 {
+            return Measure::new(
+                "system",
+                serde_json::Value::Null,
+                Some(error.unwrap_or_else(|| "the measurement was not started".to_string())),
+            );
+        }
+
+        let stats = summarize(&snapshots, &self.options);
+        if stats.noisy {
+            eprintln!(
+                "Warning: {:.1}% of the CPU time was used outside the benchmark during iteration {} of {} with {} threads, the results may be noisy",
+                stats.noise * 100.0,
+                ctx.iteration,
+                ctx.config_name,
+                ctx.thread_cnt
+            );
+        }
+        if let Some(t) = stats.throttle.as_ref().filter(|_| stats.throttled) {
+            eprintln!(
+                "Warning: the CPUs were thermally throttled ({} core, {} package events) during iteration {} of {} with {} threads",
+                t.core_throttle_count,
+                t.package_throttle_count,
+                ctx.iteration,
+                ctx.config_name,
+                ctx.thread_cnt
+            );
+        }
+
+        Measure::new("system", serde_json::to_value(stats).unwrap(), error)
+            .with_series(series(&snapshots))
+    }
+}
@@ -4,7 +4,9 @@ use serde::Serialize;
 use serde_json::Value;
 use std::{path::PathBuf, str::FromStr, time::Duration};
 
-use crate::{env::RunnerEnv, metrics::Measure, BenchConfig};
+use crate::{
+    env::RunnerEnv, metrics::Measure, BenchConfig, ConfigOverride, ConfigSource, LoadRecord,
+};
 
 #[derive(Debug, Serialize)]
 pub struct LoadResults {
@@ -15,6 +17,10 @@ pub struct LoadResults {
 #[derive(Debug, Serialize)]
 pub struct ShumaiResult<T: Serialize + Clone + BenchConfig, R: Serialize + Clone> {
     pub config: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<ConfigSource>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<ConfigOverride>,
     #[serde(rename = "load")]
     pub load_results: LoadResults,
     #[serde(rename = "cleanup")]
@@ -25,8 +31,15 @@ pub struct ShumaiResult<T: Serialize + Clone + BenchConfig, R: Serialize + Clone
 }
 
 impl<T: Serialize + Clone + BenchConfig, R: Serialize + Clone> ShumaiResult<T, R> {
-    pub(crate) fn new(config: T, load_results: LoadResults, env: RunnerEnv) -> Self {
+    pub(crate) fn new(
+        config: T,
+        record: &LoadRecord,
+        load_results: LoadResults,
+        env: RunnerEnv,
+    ) -> Self {
         Self {
+            source: record.source().cloned(),
+            overrides: record.overrides().to_vec(),
             config,
             load_results,
             cleanup_results: None,
@@ -75,6 +88,53 @@ pub struct ThreadResult<R: Serialize> {
     pub thread_cnt: usize,
     pub iterations: Vec<BenchValue<R>>,
     pub on_thread_finished: Option<Value>,
+    /// Only recorded with `SHUMAI_CALIBRATE=1`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<Calibration>,
+}
+
+/// Throughput of the iterations without any measurement, and of the iterations with each measurement alone.
+#[derive(Debug, Clone, Serialize)]
+pub struct Calibration {
+    pub baseline: ThroughputSpread,
+    pub measurements: Vec<MeasureOverhead>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MeasureOverhead {
+    pub name: String,
+    pub throughput: ThroughputSpread,
+    /// Mean throughput lost to the measurement as a fraction of the mean baseline,
+    /// differences within the spread of the runs (or negative) are run to run noise.
+    pub overhead: f64,
+}
+
+/// Throughput of the repeated calibration iterations.
+#[derive(Debug, Clone, Serialize)]
+pub struct ThroughputSpread {
+    pub mean: f64,
+    pub min: usize,
+    pub max: usize,
+    /// Sample standard deviation, 0 for a single run.
+    pub stddev: f64,
+}
+
+impl ThroughputSpread {
+    pub(crate) fn from_runs(runs: &[usize]) -> ThroughputSpread {
+        let n = runs.len() as f64;
+        let mean = runs.iter().sum::<usize>() as f64 / n;
+        let variance = if runs.len() > 1 {
+            runs.iter().map(|r| (*r as f64 - mean).powi(2)).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+        ThroughputSpread {
+            mean,
+            min: runs.iter().copied().min().unwrap_or(0),
+            max: runs.iter().copied().max().unwrap_or(0),
+            stddev: variance.sqrt(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -2,14 +2,20 @@
 
 use crate::{
     env::RunnerEnv,
-    metrics::Measurement,
-    result::{BenchValue, LoadResults, ShumaiResult, ThreadResult},
-    BenchConfig, BenchResult, Context, ShumaiBench,
+    metrics::{MeasureContext, MeasureSample, Measurement, MeasurementSet, ThreadHook},
+    result::{
+        BenchValue, Calibration, LoadResults, MeasureOverhead, ShumaiResult, ThreadResult,
+        ThroughputSpread,
+    },
+    BenchConfig, BenchResult, Context, LoadRecord, ShumaiBench,
 };
 
 use colored::Colorize;
 use std::{
-    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -32,7 +38,11 @@ struct Runner<'a, B: ShumaiBench> {
     config: &'a B::Config,
     repeat: usize,
     running_time: Duration,
+    sample_interval: Option<Duration>,
+    /// Iterations of each calibration run, `None` unless `SHUMAI_CALIBRATE` is set.
+    calibrate: Option<usize>,
     measure: Vec<Box<dyn Measurement>>,
+    thread_hooks: Vec<Arc<dyn ThreadHook>>,
 }
 
 impl<'a, B: ShumaiBench> Runner<'a, B> {
@@ -59,23 +69,21 @@ impl<'a, B: ShumaiBench> Runner<'a, B> {
             Err(_) => config.thread().to_vec(),
         };
 
-        let measurements: Vec<Box<dyn Measurement>> = vec![
-            Box::new(crate::metrics::disk_io::DiskIoMeasurement::new()),
-            #[cfg(feature = "flamegraph")]
-            Box::new(crate::metrics::flamegraph::FlamegraphMeasurement::new()),
-            #[cfg(feature = "perf")]
-            Box::new(crate::metrics::perf::PerfMeasurement::new()),
-            #[cfg(feature = "pcm")]
-            Box::new(crate::metrics::pcm::PcmMeasurement::new()),
-        ];
+        let MeasurementSet {
+            measurements,
+            thread_hooks,
+        } = crate::metrics::from_env();
 
         Self {
             f,
             config,
             repeat,
             running_time,
+            sample_interval: sample_interval(),
+            calibrate: calibrate_repeat(),
             threads,
             measure: measurements,
+            thread_hooks,
         }
     }
 
@@ -109,8 +117,12 @@ impl<'a, B: ShumaiBench> Runner<'a, B> {
             thread_cnt,
         );
 
+        let calibration = self
+            .calibrate
+            .map(|repeat| self.calibrate(thread_cnt, repeat));
+
         for i in 0..self.repeat {
-            let sample_result = self.bench_one_iter(thread_cnt);
+            let sample_result = self.bench_one_iter(thread_cnt, i, false);
 
             self.f.on_iteration_finished(i);
 
@@ -128,23 +140,113 @@ impl<'a, B: ShumaiBench> Runner<'a, B> {
             thread_cnt,
             iterations: iter_results,
             on_thread_finished,
+            calibration,
         }
     }
 
-    fn bench_one_iter(&mut self, thread_cnt: usize) -> BenchValue<B::Result> {
+    /// Runs `repeat` rounds of an iteration without measurements followed by one with each measurement alone,
+    /// and compares their throughput. Interleaving the rounds spreads a drift of the machine over all of them.
+    fn calibrate(&mut self, thread_cnt: usize, repeat: usize) -> Calibration {
+        // the measurements of the benchmark iterations, restored once calibrated
+        let saved = MeasurementSet {
+            measurements: std::mem::take(&mut self.measure),
+            thread_hooks: std::mem::take(&mut self.thread_hooks),
+        };
+
+        // measurements that are not configured (e.g. `perf_sample`) create an empty set
+        let names: Vec<_> = crate::metrics::selected_names()
+            .into_iter()
+            .filter(|name| !crate::metrics::only(name).measurements.is_empty())
+            .collect();
+        let mut baseline_runs = Vec::new();
+        let mut runs = vec![Vec::new(); names.len()];
+        for i in 0..repeat {
+            baseline_runs.push(
+                self.bench_one_iter(thread_cnt, i, true)
+                    .result
+                    .short_value(),
+            );
+            for (name, runs) in names.iter().zip(runs.iter_mut()) {
+                let MeasurementSet {
+                    measurements,
+                    thread_hooks,
+                } = crate::metrics::only(name);
+                self.measure = measurements;
+                self.thread_hooks = thread_hooks;
+                runs.push(
+                    self.bench_one_iter(thread_cnt, i, true)
+                        .result
+                        .short_value(),
+                );
+            }
+        }
+
+        let baseline = ThroughputSpread::from_runs(&baseline_runs);
+        println!(
+            "{} {:.0} (stddev {:.0})",
+            "Calibration baseline:".cyan(),
+            baseline.mean,
+            baseline.stddev
+        );
+        let measurements = names
+            .into_iter()
+            .zip(runs)
+            .map(|(name, runs)| {
+                let throughput = ThroughputSpread::from_runs(&runs);
+                let overhead = if baseline.mean > 0.0 {
+                    1.0 - throughput.mean / baseline.mean
+                } else {
+                    0.0
+                };
+                println!(
+                    "{} {:.0} (stddev {:.0}, {:.1}% overhead)",
+                    format!("Calibration {}:", name).cyan(),
+                    throughput.mean,
+                    throughput.stddev,
+                    overhead * 100.0
+                );
+                MeasureOverhead {
+                    name: name.to_string(),
+                    throughput,
+                    overhead,
+                }
+            })
+            .collect();
+
+        self.measure = saved.measurements;
+        self.thread_hooks = saved.thread_hooks;
+        Calibration {
+            baseline,
+            measurements,
+        }
+    }
+
+    /// `calibrating` iterations only measure the overhead of the measurements, their profiles are not written.
+    fn bench_one_iter(
+        &mut self,
+        thread_cnt: usize,
+        iteration: usize,
+        calibrating: bool,
+    ) -> BenchValue<B::Result> {
         let ready_thread = AtomicU64::new(0);
         let is_running = AtomicBool::new(false);
 
         std::thread::scope(|scope| {
             let _thread_guard = ThreadPoison;
+            let bench = &*self.f;
+            let thread_hooks = &self.thread_hooks;
             let handlers: Vec<_> = (0..thread_cnt)
                 .map(|tid| {
                     let context =
                         Context::new(tid, thread_cnt, self.config, &ready_thread, &is_running);
-                    scope.spawn(|| {
+                    scope.spawn(move || {
                         let _thread_guard = ThreadPoison;
 
-                        self.f.run(context)
+                        for h in thread_hooks.iter() {
+                            h.on_thread_start(tid);
+                        }
+
+                        bench.run(context)
                     })
                 })
                 .collect();
@@ -161,15 +263,33 @@ impl<'a, B: ShumaiBench> Runner<'a, B> {
             is_running.store(true, Ordering::SeqCst);
 
             let start_time = Instant::now();
+            let mut series: Vec<Vec<MeasureSample>> = vec![Vec::new(); self.measure.len()];
+            let mut last_sample = start_time;
 
             while (Instant::now() - start_time) < self.running_time {
-                std::thread::sleep(Duration::from_millis(50));
+                let mut sleep = Duration::from_millis(50);
+                if let Some(interval) = self.sample_interval {
+                    let now = Instant::now();
+                    if now - last_sample >= interval {
+                        sample(&mut self.measure, &mut series, start_time, last_sample, now);
+                        last_sample = now;
+                    }
+                    sleep = sleep.min((last_sample + interval).saturating_duration_since(now));
+                }
+                std::thread::sleep(sleep);
             }
 
             // stop the world!
             is_running.store(false, Ordering::SeqCst);
 
-            for i in self.measure.iter_mut() {
+            // the last sample covers the time since the previous one, so the series adds up to the run
+            if self.sample_interval.is_some() {
+                let now = Instant::now();
+                sample(&mut self.measure, &mut series, start_time, last_sample, now);
+            }
+
+            // in the reverse start order, see `metrics::MEASUREMENTS`
+            for i in self.measure.iter_mut().rev() {
                 i.stop();
             }
 
@@ -183,7 +303,29 @@ impl<'a, B: ShumaiBench> Runner<'a, B> {
                 v + h.clone().normalize_time(&self.running_time)
             });
 
-            let measurements = self.measure.iter_mut().map(|m| m.result()).collect();
+            let thread_ops: Vec<_> = all_results.iter().map(|r| r.short_value()).collect();
+            let ctx = MeasureContext {
+                ops: thread_ops.iter().sum(),
+                thread_ops,
+                config_name: self.config.name().clone(),
+                thread_cnt,
+                iteration,
+                calibrating,
+            };
+            let measurements = self
+                .measure
+                .iter_mut()
+                .zip(series)
+                .map(|(m, series)| {
+                    let measure = m.result(&ctx);
+                    // collectors with their own sampler are not sampled by the runner
+                    if series.is_empty() {
+                        measure
+                    } else {
+                        measure.with_series(series)
+                    }
+                })
+                .collect();
 
             BenchValue {
                 result: thrput,
@@ -198,11 +340,23 @@ pub fn run<B: ShumaiBench>(
     bench: &mut B,
     config: &B::Config,
     repeat: usize,
+) -> ShumaiResult<B::Config, B::Result> {
+    run_loaded(bench, config, &LoadRecord::default(), repeat)
+}
+
+/// Same as [`run`], and also records where `config` was loaded from and its overrides,
+/// e.g. `run_loaded(&mut bench, &configs[0], configs.record(), repeat)`.
+#[must_use = "bench function returns the bench results"]
+pub fn run_loaded<B: ShumaiBench>(
+    bench: &mut B,
+    config: &B::Config,
+    record: &LoadRecord,
+    repeat: usize,
 ) -> ShumaiResult<B::Config, B::Result> {
     let mut runner = Runner::new(bench, config, repeat);
     let load_results = runner.load();
     let mut results: ShumaiResult<B::Config, B::Result> =
-        ShumaiResult::new(config.clone(), load_results, RunnerEnv::new());
+        ShumaiResult::new(config.clone(), record, load_results, RunnerEnv::new());
 
     let threads = runner.threads();
     for t in threads {
@@ -216,6 +370,57 @@ pub fn run<B: ShumaiBench>(
     results
 }
 
+/// Samples every measurement, the samples cover `[from, to]` of the run started at `start_time`.
+fn sample(
+    measure: &mut [Box<dyn Measurement>],
+    series: &mut [Vec<MeasureSample>],
+    start_time: Instant,
+    from: Instant,
+    to: Instant,
+) {
+    for (m, s) in measure.iter_mut().zip(series.iter_mut()) {
+        if let Some(value) = m.sample() {
+            s.push(MeasureSample {
+                start_ms: (from - start_time).as_millis() as u64,
+                end_ms: (to - start_time).as_millis() as u64,
+                value,
+            });
+        }
+    }
+}
+
+/// Milliseconds between two calls to `Measurement::sample`, no sampling unless it is set.
+fn sample_interval() -> Option<Duration> {
+    let ms = std::env::var("SHUMAI_SAMPLE_INTERVAL_MS").ok()?;
+    Some(Duration::from_millis(
+        ms.parse::<u64>()
+            .ok()
+            .filter(|ms| *ms > 0)
+            .expect("SHUMAI_SAMPLE_INTERVAL_MS must be a positive number"),
+    ))
+}
+
+const DEFAULT_CALIBRATE_REPEAT: usize = 3;
+
+/// `SHUMAI_CALIBRATE=1` measures the throughput overhead of every measurement before each thread count,
+/// over `SHUMAI_CALIBRATE_REPEAT` iterations of the baseline and of each measurement.
+fn calibrate_repeat() -> Option<usize> {
+    if !matches!(
+        std::env::var("SHUMAI_CALIBRATE").as_deref(),
+        Ok("1") | Ok("true")
+    ) {
+        return None;
+    }
+    Some(match std::env::var("SHUMAI_CALIBRATE_REPEAT") {
+        Ok(n) => n
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .expect("SHUMAI_CALIBRATE_REPEAT must be a positive number"),
+        Err(_) => DEFAULT_CALIBRATE_REPEAT,
+    })
+}
+
 fn is_profile_by_time() -> Option<usize> {
     let profile_time = std::env::var("PROFILE_TIME").ok()?;
     profile_time.parse::<usize>().ok()
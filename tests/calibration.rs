@@ -0,0 +1,133 @@
+//! `SHUMAI_CALIBRATE` applies to every benchmark of the process, so it is tested in its own binary.
+
+use serde_json::Value;
+use shumai::{config, Context, ShumaiBench};
+
+#[config(path = "tests/benchmark.toml")]
+pub struct Calibrated {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+}
+
+struct CountBench;
+
+impl ShumaiBench for CountBench {
+    type Result = usize;
+    type Config = Calibrated;
+
+    fn load(&mut self) -> Option<Value> {
+        None
+    }
+
+    fn run(&self, context: Context<Calibrated>) -> Self::Result {
+        context.wait_for_start();
+        let mut ops = 0;
+        while context.is_running() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            ops += 1;
+        }
+        ops
+    }
+
+    fn cleanup(&mut self) -> Option<Value> {
+        None
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn calibration() {
+    let config = Calibrated::load_from_str(
+        r#"[[Calibrated]]
+name = "c"
+threads = [1]
+time = 1
+"#,
+    )
+    .expect("Failed to parse config!");
+
+    let result = shumai::run(&mut CountBench, &config[0], 1);
+    let result: Value = serde_json::from_str(&result.to_json()).unwrap();
+    assert!(result["run"][0].get("calibration").is_none());
+
+    std::env::set_var("SHUMAI_CALIBRATE", "1");
+    std::env::set_var("SHUMAI_CALIBRATE_REPEAT", "2");
+    #[cfg(not(feature = "flamegraph"))]
+    std::env::set_var("SHUMAI_MEASURE", "memory,disk_io");
+    #[cfg(feature = "flamegraph")]
+    std::env::set_var("SHUMAI_MEASURE", "memory,disk_io,flamegraph");
+    #[cfg(feature = "flamegraph")]
+    let profiles_before = profiles();
+    let result = shumai::run(&mut CountBench, &config[0], 1);
+    let result: Value = serde_json::from_str(&result.to_json()).unwrap();
+
+    let thread = &result["run"][0];
+    assert_eq!(thread["iterations"].as_array().unwrap().len(), 1);
+    let calibration = &thread["calibration"];
+    assert_spread(&calibration["baseline"]);
+    let measurements = calibration["measurements"].as_array().unwrap();
+    let names: Vec<&str> = measurements
+        .iter()
+        .map(|m| m["name"].as_str().unwrap())
+        .collect();
+    #[cfg(not(feature = "flamegraph"))]
+    assert_eq!(names, ["disk_io", "memory"]);
+    #[cfg(feature = "flamegraph")]
+    assert_eq!(names, ["flamegraph", "disk_io", "memory"]);
+    for m in measurements {
+        assert_spread(&m["throughput"]);
+        let overhead = m["overhead"].as_f64().unwrap();
+        assert!(overhead < 1.0, "{}", m);
+    }
+
+    // only the benchmark iteration writes a profile
+    #[cfg(feature = "flamegraph")]
+    {
+        let mut written: Vec<_> = profiles()
+            .into_iter()
+            .filter(|p| !profiles_before.contains(p))
+            .collect();
+        let iteration = result["run"][0]["iterations"][0]["measurements"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|m| m["name"] == "flamegraph")
+            .unwrap();
+        let mut reported: Vec<_> = iteration["value"]
+            .as_object()
+            .unwrap()
+            .values()
+            .map(|p| std::fs::canonicalize(p.as_str().unwrap()).unwrap())
+            .collect();
+        reported.sort();
+        written.sort();
+        assert_eq!(written, reported);
+    }
+}
+
+/// The mean, min and max of the repeated runs, in order.
+fn assert_spread(spread: &Value) {
+    let mean = spread["mean"].as_f64().unwrap();
+    assert!(mean > 0.0, "{}", spread);
+    assert!(spread["min"].as_u64().unwrap() as f64 <= mean);
+    assert!(spread["max"].as_u64().unwrap() as f64 >= mean);
+    assert!(spread["stddev"].as_f64().unwrap() >= 0.0);
+}
+
+/// Every profile written so far.
+#[cfg(feature = "flamegraph")]
+fn profiles() -> Vec<std::path::PathBuf> {
+    let root = std::path::Path::new("target/benchmark");
+    let mut profiles = Vec::new();
+    for day in std::fs::read_dir(root).into_iter().flatten().flatten() {
+        for file in std::fs::read_dir(day.path())
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            profiles.push(std::fs::canonicalize(file.path()).unwrap());
+        }
+    }
+    profiles
+}
@@ -0,0 +1,7 @@
+#[test]
+#[cfg_attr(miri, ignore)]
+fn config_compile_errors() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+    t.pass("tests/ui/pass/*.rs");
+}
@@ -0,0 +1,338 @@
+use shumai::{config, ConfigError, OverrideSource, Validate};
+
+#[config(path = "tests/benchmark.toml")]
+pub struct Foo {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+    #[matrix]
+    pub a: usize,
+    #[matrix]
+    pub c: Option<usize>,
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn override_from_args() {
+    let config = Foo::load_with_args(args(&[
+        "bench",
+        "--set",
+        "time=5",
+        "--set=a=[3, 4, 5]",
+        "--set",
+        "c=7",
+    ]))
+    .expect("Failed to parse config!");
+
+    // two entries in the file, each expanded to three values of `a`
+    assert_eq!(config.len(), 6);
+    for c in config.iter() {
+        assert_eq!(c.time, 5);
+        assert_eq!(c.c, Some(7));
+    }
+    let a: Vec<_> = config.iter().map(|c| c.a).collect();
+    assert_eq!(a, vec![3, 4, 5, 3, 4, 5]);
+
+    let overrides = config.overrides();
+    assert_eq!(overrides.len(), 3);
+    assert_eq!(overrides[0].field, "time");
+    assert_eq!(overrides[0].value, "5");
+    assert_eq!(overrides[0].source, OverrideSource::Cli);
+
+    // later loads, successful or not, don't change the overrides of configs loaded before
+    let plain = Foo::load().expect("Failed to parse config!");
+    assert!(plain.overrides().is_empty());
+    Foo::load_with_args(args(&["--set", "b=1"])).unwrap_err();
+    assert_eq!(config.overrides(), overrides);
+}
+
+#[config(path = "tests/benchmark.toml")]
+pub struct Extra {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+    pub extra: usize,
+}
+
+#[test]
+fn env_override_other_struct() {
+    // only `Extra` has the field, the other config structs of the binary ignore the variable
+    std::env::set_var("SHUMAI_SET_EXTRA", "3");
+    let extra = Extra::load_from_str(
+        r#"[[Extra]]
+name = "e"
+threads = [1]
+time = 1
+extra = 1
+"#,
+    );
+    let foo = Foo::load_from_str(
+        r#"[[Foo]]
+name = "f"
+threads = [1]
+time = 1
+a = [1]
+"#,
+    );
+    std::env::remove_var("SHUMAI_SET_EXTRA");
+
+    let extra = extra.expect("Failed to parse config!");
+    assert_eq!(extra[0].extra, 3);
+    let overrides = extra.overrides();
+    assert_eq!(overrides.len(), 1);
+    assert_eq!(overrides[0].field, "extra");
+    assert_eq!(overrides[0].source, OverrideSource::Env);
+
+    let foo = foo.expect("Failed to parse config!");
+    assert!(foo.overrides().is_empty());
+}
+
+#[test]
+fn override_unknown_field() {
+    let err = Foo::load_with_args(args(&["--set", "b=1"])).unwrap_err();
+    assert!(matches!(err, ConfigError::Override(_)));
+    assert!(err.to_string().contains("Unknown field `b`"));
+}
+
+#[test]
+fn override_wrong_type() {
+    let err = Foo::load_with_args(args(&["--set", "time=forever"])).unwrap_err();
+    assert!(matches!(err, ConfigError::Parse { .. }));
+}
+
+#[test]
+fn parse_error_location() {
+    let err = Foo::load_from_str(
+        r#"[[Foo]]
+name = "foo"
+threads = [1]
+time = 1
+a = [1]
+b = 2
+"#,
+    )
+    .unwrap_err();
+    match err {
+        ConfigError::Parse { line, message, .. } => {
+            assert_eq!(line, Some(6));
+            assert!(message.contains("unknown field `b`"), "{}", message);
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+
+    let err = Foo::load_from("tests/missing.toml").unwrap_err();
+    assert!(matches!(err, ConfigError::Io { .. }));
+
+    let err = Foo::load_from_str("[[Bar]]\nname = \"bar\"").unwrap_err();
+    assert!(matches!(err, ConfigError::MissingTable { .. }));
+}
+
+#[config(path = "tests/benchmark.toml", validate)]
+pub struct Validated {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+    #[matrix]
+    pub a: usize,
+}
+
+impl Validate for Validated {
+    fn validate(&self) -> Result<(), String> {
+        if self.a > 4 {
+            return Err(format!("a must be at most 4, got {}", self.a));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn validate_hook() {
+    let config = Validated::load_from_str(
+        r#"[[Validated]]
+name = "ok"
+threads = [1]
+time = 1
+a = [1, 4]
+"#,
+    )
+    .expect("Failed to parse config!");
+    assert_eq!(config.len(), 2);
+
+    let err = Validated::load_from_str(
+        r#"[[Validated]]
+name = "bad"
+threads = [1]
+time = 1
+a = [1, 8]
+"#,
+    )
+    .unwrap_err();
+    match err {
+        ConfigError::Validation { name, message } => {
+            assert_eq!(name, "validated-bad-8");
+            assert_eq!(message, "a must be at most 4, got 8");
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+}
+
+#[test]
+fn load_from_str() {
+    let config = Foo::load_from_str(
+        r#"
+        [[Foo]]
+        name = "inline"
+        threads = [4]
+        time = 2
+        a = [8]
+        "#,
+    )
+    .expect("Failed to parse config!");
+    assert_eq!(config.len(), 1);
+    assert_eq!(config[0].name, "foo-inline");
+    assert_eq!(config[0].threads, vec![4]);
+
+    let source = config.source().unwrap();
+    assert_eq!(source.path, None);
+    assert_eq!(source.sha256.len(), 64);
+}
+
+#[test]
+fn load_from_env_path() {
+    // same content as the default file, so concurrent tests loading the default path are unaffected
+    let path = std::env::temp_dir().join("shumai-config-env-test.toml");
+    std::fs::copy("tests/benchmark.toml", &path).unwrap();
+    std::env::set_var("SHUMAI_CONFIG", &path);
+
+    let config = Foo::load();
+    std::env::remove_var("SHUMAI_CONFIG");
+    let config = config.expect("Failed to parse config!");
+    let source = config.source().unwrap();
+    assert_eq!(source.path, Some(std::fs::canonicalize(&path).unwrap()));
+
+    let from_default = Foo::load_from("tests/benchmark.toml").expect("Failed to parse config!");
+    assert_eq!(from_default.source().unwrap().sha256, source.sha256);
+}
+
+#[test]
+fn source_per_load() {
+    let path = std::env::temp_dir().join("shumai-config-source-test.toml");
+    std::fs::write(
+        &path,
+        "[[Foo]]\nname = \"file\"\nthreads = [1]\ntime = 1\na = [1]\n",
+    )
+    .unwrap();
+    let from_file = Foo::load_from(&path).expect("Failed to parse config!");
+
+    // neither a later load nor a failed one changes the source of the configs loaded before
+    let from_str = Foo::load_from_str(
+        r#"[[Foo]]
+name = "str"
+threads = [1]
+time = 1
+a = [1]
+"#,
+    )
+    .expect("Failed to parse config!");
+    Foo::load_from_str("[[Foo]]\nname = \"bad\"").unwrap_err();
+
+    let source = from_file.source().unwrap();
+    assert_eq!(source.path, Some(std::fs::canonicalize(&path).unwrap()));
+    assert_eq!(from_str.source().unwrap().path, None);
+    assert_ne!(from_str.source().unwrap().sha256, source.sha256);
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Distribution {
+    Uniform,
+    Zipf { theta: f64 },
+}
+
+#[config(section)]
+pub struct Storage {
+    pub path: String,
+    #[matrix]
+    pub cache_mb: usize,
+}
+
+#[config(section)]
+pub struct Workload {
+    #[matrix]
+    pub distribution: Distribution,
+    pub read_ratio: f64,
+}
+
+#[config(path = "tests/benchmark.toml")]
+pub struct Nested {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+    #[matrix]
+    pub a: usize,
+    #[nested]
+    pub storage: Storage,
+    #[nested]
+    pub workload: Workload,
+}
+
+#[test]
+fn nested_sections() {
+    let config = Nested::load_from_str(
+        r#"[[Nested]]
+name = "n"
+threads = [1]
+time = 1
+a = [1, 2]
+
+[Nested.storage]
+path = "/tmp"
+cache_mb = [64, 128]
+
+[Nested.workload]
+distribution = ["Uniform", { Zipf = { theta = 0.99 } }]
+read_ratio = 0.5
+"#,
+    )
+    .expect("Failed to parse config!");
+
+    assert_eq!(config.len(), 8);
+    assert_eq!(config[0].name, "nested-n-1-64-Uniform");
+    assert_eq!(config[1].name, "nested-n-1-64-Zipf { theta: 0.99 }");
+    assert_eq!(config[7].name, "nested-n-2-128-Zipf { theta: 0.99 }");
+    for c in config.iter() {
+        assert_eq!(c.storage.path, "/tmp");
+        assert_eq!(c.workload.read_ratio, 0.5);
+    }
+    assert_eq!(config[2].storage.cache_mb, 128);
+    assert_eq!(
+        config[3].workload.distribution,
+        Distribution::Zipf { theta: 0.99 }
+    );
+
+    let err = Nested::load_from_str(
+        r#"[[Nested]]
+name = "n"
+threads = [1]
+time = 1
+a = [1]
+
+[Nested.storage]
+path = "/tmp"
+cache_mb = [64]
+cache_gb = [1]
+
+[Nested.workload]
+distribution = ["Uniform"]
+read_ratio = 0.5
+"#,
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("unknown field `cache_gb`"),
+        "{}",
+        err
+    );
+}
@@ -0,0 +1,95 @@
+//! `SHUMAI_MEASURE` applies to every benchmark of the process, so it is tested in its own binary.
+
+use serde_json::Value;
+use shumai::{config, Context, ShumaiBench};
+
+#[config(path = "tests/benchmark.toml")]
+pub struct Selected {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+}
+
+struct IdleBench;
+
+impl ShumaiBench for IdleBench {
+    type Result = usize;
+    type Config = Selected;
+
+    fn load(&mut self) -> Option<Value> {
+        None
+    }
+
+    fn run(&self, context: Context<Selected>) -> Self::Result {
+        context.wait_for_start();
+        while context.is_running() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        1
+    }
+
+    fn cleanup(&mut self) -> Option<Value> {
+        None
+    }
+}
+
+/// Names of the measurements recorded by a one second benchmark.
+fn measurement_names() -> Vec<String> {
+    let config = Selected::load_from_str(
+        r#"[[Selected]]
+name = "s"
+threads = [1]
+time = 1
+"#,
+    )
+    .expect("Failed to parse config!");
+    let result = shumai::run(&mut IdleBench, &config[0], 1);
+    let result: Value = serde_json::from_str(&result.to_json()).unwrap();
+    result["run"][0]["iterations"][0]["measurements"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn measure_selection() {
+    let all = measurement_names();
+    for name in ["disk_io", "net_io", "memory", "cpu", "cgroup", "energy"] {
+        assert!(
+            all.iter().any(|m| m == name),
+            "{} missing in {:?}",
+            name,
+            all
+        );
+    }
+    // the collectors sampling or profiling the run only run when selected
+    for name in ["system", "flamegraph", "perf", "perf_sample", "pcm"] {
+        assert!(!all.iter().any(|m| m == name), "{:?}", all);
+    }
+
+    std::env::set_var("SHUMAI_MEASURE", "memory, disk_io");
+    assert_eq!(measurement_names(), ["disk_io", "memory"]);
+
+    std::env::set_var("SHUMAI_MEASURE", "none");
+    assert!(measurement_names().is_empty());
+
+    std::env::set_var("SHUMAI_MEASURE", "disk_io,not_a_measurement");
+    let err = std::panic::catch_unwind(measurement_names).unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(
+        msg.contains("unknown measurement not_a_measurement"),
+        "{}",
+        msg
+    );
+
+    #[cfg(not(feature = "pcm"))]
+    {
+        std::env::set_var("SHUMAI_MEASURE", "pcm");
+        let err = std::panic::catch_unwind(measurement_names).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("needs the `pcm` cargo feature"), "{}", msg);
+    }
+}
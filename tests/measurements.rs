@@ -0,0 +1,881 @@
+use serde_json::Value;
+use shumai::{config, Context, ShumaiBench};
+use std::ffi::{OsStr, OsString};
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(feature = "alloc")]
+#[global_allocator]
+static ALLOC: shumai::alloc::CountingAllocator = shumai::alloc::CountingAllocator::system();
+
+#[config(path = "tests/benchmark.toml")]
+pub struct Measured {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+}
+
+/// A benchmark running `F` in every thread, `F` waits for the start and returns the ops of its thread.
+struct Bench<F>(F);
+
+impl<F> ShumaiBench for Bench<F>
+where
+    F: Fn(Context<'_, Measured>) -> usize + Send + Sync,
+{
+    type Result = usize;
+    type Config = Measured;
+
+    fn load(&mut self) -> Option<Value> {
+        None
+    }
+
+    fn run(&self, context: Context<Measured>) -> Self::Result {
+        (self.0)(context)
+    }
+
+    fn cleanup(&mut self) -> Option<Value> {
+        None
+    }
+}
+
+/// Runs `op` until the benchmark stops or it ran `max_ops` times, and returns how often it ran.
+fn repeat(context: &Context<Measured>, max_ops: usize, mut op: impl FnMut()) -> usize {
+    let mut ops = 0;
+    while context.is_running() && ops < max_ops {
+        op();
+        ops += 1;
+    }
+    ops
+}
+
+/// The measurements read their options from the environment of the process, so the tests that
+/// run a benchmark hold this lock, and the variables they set are restored when they finish.
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+struct Env {
+    saved: Vec<(&'static str, Option<OsString>)>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Env {
+    fn lock() -> Env {
+        Env {
+            saved: Vec::new(),
+            // a failed test doesn't leave the environment behind, the next one can go on
+            _lock: ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+
+    fn set(&mut self, key: &'static str, value: impl AsRef<OsStr>) {
+        if !self.saved.iter().any(|(k, _)| *k == key) {
+            self.saved.push((key, std::env::var_os(key)));
+        }
+        std::env::set_var(key, value);
+    }
+
+    /// Runs a one second benchmark counting until it stops, and returns the measurements of its only iteration.
+    fn measure(&self, threads: usize) -> Vec<Value> {
+        self.measure_with(threads, |context| {
+            context.wait_for_start();
+            repeat(&context, usize::MAX, || {})
+        })
+    }
+
+    /// Same as `measure`, with `run` as the benchmark of every thread.
+    fn measure_with<F>(&self, threads: usize, run: F) -> Vec<Value>
+    where
+        F: Fn(Context<'_, Measured>) -> usize + Send + Sync,
+    {
+        let config = Measured::load_from_str(&format!(
+            r#"[[Measured]]
+name = "m"
+threads = [{}]
+time = 1
+"#,
+            threads
+        ))
+        .expect("Failed to parse config!");
+        let result = shumai::run(&mut Bench(run), &config[0], 1);
+        let result: Value = serde_json::from_str(&result.to_json()).unwrap();
+        result["run"][0]["iterations"][0]["measurements"]
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        for (key, value) in self.saved.drain(..).rev() {
+            match value {
+                Some(v) => std::env::set_var(key, v),
+                None => std::env::remove_var(key),
+            }
+        }
+    }
+}
+
+fn find<'a>(measurements: &'a [Value], name: &str) -> &'a Value {
+    &measurements
+        .iter()
+        .find(|m| m["name"] == name)
+        .unwrap_or_else(|| panic!("measurement {} not found", name))["value"]
+}
+
+fn find_series<'a>(measurements: &'a [Value], name: &str) -> &'a [Value] {
+    measurements.iter().find(|m| m["name"] == name).unwrap()["series"]
+        .as_array()
+        .unwrap_or_else(|| panic!("no series for {}", name))
+}
+
+/// Writes and reads back a small file, at most 1000 times.
+fn write_file(context: Context<Measured>) -> usize {
+    use std::io::{Read, Seek, Write};
+
+    let path = std::env::temp_dir().join(format!(
+        "shumai-disk-io-test-{}-{}",
+        std::process::id(),
+        context.thread_id
+    ));
+    let mut file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    context.wait_for_start();
+    let mut buf = [0u8; 4096];
+    let ops = repeat(&context, 1000, || {
+        file.rewind().unwrap();
+        file.write_all(&buf).unwrap();
+        file.rewind().unwrap();
+        file.read_exact(&mut buf).unwrap();
+    });
+    std::fs::remove_file(&path).unwrap();
+    ops
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn disk_io() {
+    let mut env = Env::lock();
+    let measurements = env.measure_with(1, write_file);
+    let disk_io = find(&measurements, "disk_io");
+    assert!(disk_io["wchar"].as_u64().unwrap() >= 4096 * 1000);
+    assert!(disk_io["rchar"].as_u64().unwrap() >= 4096 * 1000);
+    assert!(disk_io["syscw"].as_u64().unwrap() >= 1000);
+    assert!(disk_io["read_bytes"].is_u64());
+    assert!(disk_io["write_bytes"].is_u64());
+    assert!(disk_io["cancelled_write_bytes"].is_u64());
+    assert!(disk_io["device"].is_null());
+
+    let diskstats = std::fs::read_to_string("/proc/diskstats").unwrap();
+    let device = diskstats.split_whitespace().nth(2).unwrap();
+    env.set("SHUMAI_DISK_DEVICE", device);
+    let measurements = env.measure(1);
+    let stats = &find(&measurements, "disk_io")["device"];
+    assert_eq!(stats["device"], device);
+    assert!(stats["read_iops"].is_f64());
+    assert!(stats["write_bandwidth"].is_f64());
+    assert!(stats["utilization"].as_f64().unwrap() <= 1.0);
+
+    env.set("SHUMAI_DISK_DEVICE", "not-a-device");
+    let measurements = env.measure(1);
+    let disk_io = measurements
+        .iter()
+        .find(|m| m["name"] == "disk_io")
+        .unwrap();
+    assert!(disk_io["value"].is_null());
+    assert!(disk_io["error"]
+        .as_str()
+        .unwrap()
+        .contains("not-a-device not found"));
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn memory() {
+    // allocates and touches `BYTES` during the run, and frees it before returning
+    const BYTES: usize = 64 * 1024 * 1024;
+
+    let env = Env::lock();
+    let measurements = env.measure_with(1, |context| {
+        context.wait_for_start();
+        let mut buf = vec![0u8; BYTES];
+        for i in (0..buf.len()).step_by(4096) {
+            buf[i] = 1;
+        }
+        repeat(&context, usize::MAX, std::hint::spin_loop);
+        buf.iter().step_by(4096).map(|b| *b as usize).sum()
+    });
+    let memory = find(&measurements, "memory");
+
+    let rss_start = memory["rss_start"].as_u64().unwrap();
+    let peak_rss = memory["peak_rss"].as_u64().unwrap();
+    assert!(rss_start > 0);
+    assert!(memory["rss_end"].as_u64().unwrap() > 0);
+    assert!(peak_rss >= rss_start + BYTES as u64 / 2, "{}", memory);
+    assert!(memory["minor_faults"].as_u64().unwrap() >= (BYTES / 4096 / 2) as u64);
+    assert!(memory["major_faults"].is_u64());
+    assert!(memory["peak_reset"].is_boolean());
+    assert!(memory["rss_anon"].as_u64().unwrap() > 0);
+    assert!(memory["rss_file"].is_u64());
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn cpu() {
+    let env = Env::lock();
+    let measurements = env.measure(2);
+    let cpu = find(&measurements, "cpu");
+
+    let threads = cpu["threads"].as_array().unwrap();
+    assert_eq!(threads.len(), 2);
+    for (tid, t) in threads.iter().enumerate() {
+        assert_eq!(t["tid"], tid);
+        assert!(t["on_cpu_ms"].as_f64().unwrap() > 0.0);
+        assert!(t["runqueue_wait_ms"].as_f64().unwrap() >= 0.0);
+        assert!(t["utilization"].as_f64().unwrap() > 0.0);
+    }
+    // the threads spin for the whole window, unless the machine is oversubscribed
+    let utilization = cpu["utilization"].as_f64().unwrap();
+    assert!(utilization > 0.0 && utilization <= 1.05, "{}", utilization);
+    assert!(cpu["user_time_ms"].as_f64().unwrap() > 0.0);
+    assert!(cpu["system_time_ms"].is_f64());
+    assert!(cpu["voluntary_switches"].is_u64());
+    assert!(cpu["involuntary_switches"].is_u64());
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn series() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_SAMPLE_INTERVAL_MS", "200");
+    #[cfg(feature = "perf")]
+    env.set("SHUMAI_MEASURE", "disk_io,memory,cpu,perf");
+    let measurements = env.measure(1);
+
+    let disk_io = find_series(&measurements, "disk_io");
+    assert!(!disk_io.is_empty());
+    assert_eq!(disk_io[0]["start_ms"], 0);
+    assert!(disk_io
+        .windows(2)
+        .all(|w| w[0]["end_ms"] == w[1]["start_ms"]));
+    // every interval is sampled, and a last sample covers the rest of the one second run
+    let (last, full) = disk_io.split_last().unwrap();
+    assert!(full
+        .iter()
+        .all(|s| s["end_ms"].as_u64().unwrap() - s["start_ms"].as_u64().unwrap() >= 200));
+    assert!(last["end_ms"].as_u64().unwrap() >= 1000);
+    assert!(disk_io[0]["value"]["rchar"].is_u64());
+
+    let memory = find_series(&measurements, "memory");
+    assert_eq!(memory.len(), disk_io.len());
+    assert!(memory
+        .iter()
+        .all(|s| s["value"]["rss"].as_u64().unwrap() > 0));
+    assert!(memory[0]["value"]["minor_faults"].is_u64());
+
+    #[cfg(feature = "perf")]
+    assert!(find_series(&measurements, "perf")[0]["value"]["counters"].is_object());
+
+    // measurements without a `sample` hook have no series
+    let cpu = measurements.iter().find(|m| m["name"] == "cpu").unwrap();
+    assert!(cpu.get("series").is_none());
+}
+
+fn write_cgroup(root: &std::path::Path, usage_usec: u64, memory: u64, rbytes: u64, some_us: u64) {
+    let write = |file: &str, content: String| std::fs::write(root.join(file), content).unwrap();
+    write(
+        "cpu.stat",
+        format!(
+            "usage_usec {}\nuser_usec {}\nsystem_usec 0\nnr_periods 0\nnr_throttled 0\nthrottled_usec 0\n",
+            usage_usec, usage_usec
+        ),
+    );
+    write("memory.current", format!("{}\n", memory));
+    write("memory.peak", format!("{}\n", memory));
+    write(
+        "io.stat",
+        format!(
+            "8:0 rbytes={} wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n",
+            rbytes
+        ),
+    );
+    write(
+        "cpu.pressure",
+        format!(
+            "some avg10=0.00 avg60=0.00 avg300=0.00 total={}\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+            some_us
+        ),
+    );
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn cgroup() {
+    let mut env = Env::lock();
+    let measurements = env.measure(1);
+    let cgroup = measurements.iter().find(|m| m["name"] == "cgroup").unwrap();
+    assert!(cgroup["value"].is_object() != cgroup["error"].is_string());
+    if let Some(usage) = cgroup["value"]["cpu"]["usage_usec"].as_u64() {
+        assert!(usage > 0, "{}", cgroup);
+    }
+
+    let root = std::env::temp_dir().join(format!("shumai-cgroup-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    write_cgroup(&root, 1000, 1 << 20, 100, 1000);
+    env.set("SHUMAI_CGROUP_PATH", &root);
+    // the fake cgroup is updated once the run started
+    let measurements = env.measure_with(1, |context| {
+        context.wait_for_start();
+        write_cgroup(&root, 501_000, 3 << 20, 4196, 101_000);
+        repeat(&context, usize::MAX, || {})
+    });
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let cgroup = find(&measurements, "cgroup");
+    assert_eq!(cgroup["path"], root.display().to_string());
+    assert_eq!(cgroup["cpu"]["usage_usec"], 500_000, "{}", cgroup);
+    assert_eq!(cgroup["cpu"]["user_usec"], 500_000);
+    assert_eq!(cgroup["memory"]["current_start"], 1 << 20);
+    assert_eq!(cgroup["memory"]["current_end"], 3 << 20);
+    // reads through the file opened at the start see the rewritten peak
+    assert_eq!(cgroup["memory"]["peak"], 3 << 20);
+    assert_eq!(cgroup["memory"]["peak_reset"], true);
+    assert_eq!(cgroup["io"][0]["device"], "8:0");
+    assert_eq!(cgroup["io"][0]["rbytes"], 4096);
+    assert_eq!(cgroup["io"][0]["rios"], 0);
+    assert_eq!(cgroup["cpu_pressure"]["some_ms"], 100.0);
+    assert_eq!(cgroup["cpu_pressure"]["full_ms"], 0.0);
+    // 100ms of pressure in a window of at least a second
+    let fraction = cgroup["cpu_pressure"]["some_fraction"].as_f64().unwrap();
+    assert!(fraction > 0.0 && fraction <= 0.1, "{}", fraction);
+    assert!(cgroup["memory_pressure"].is_null());
+    assert!(cgroup["io_pressure"].is_null());
+
+    // the fake cgroup is gone
+    let measurements = env.measure(1);
+    let cgroup = measurements.iter().find(|m| m["name"] == "cgroup").unwrap();
+    assert!(cgroup["value"].is_null());
+    assert!(cgroup["error"].as_str().unwrap().contains("does not exist"));
+}
+
+/// Sends 1KB over a loopback TCP connection and reads it back on the other end, at most 1000 times.
+fn loopback(context: Context<Measured>) -> usize {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    client.set_nodelay(true).unwrap();
+    context.wait_for_start();
+    let mut buf = [0u8; 1024];
+    repeat(&context, 1000, || {
+        client.write_all(&buf).unwrap();
+        server.read_exact(&mut buf).unwrap();
+    })
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn net_io() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_NET_INTERFACE", "lo");
+    let measurements = env.measure_with(1, loopback);
+    let net_io = find(&measurements, "net_io");
+    let interfaces = net_io["interfaces"].as_array().unwrap();
+    assert_eq!(interfaces.len(), 1);
+    assert_eq!(interfaces[0]["interface"], "lo");
+    // loopback packets are counted as both sent and received
+    assert!(
+        net_io["rx_bytes"].as_u64().unwrap() >= 1024 * 1000,
+        "{}",
+        net_io
+    );
+    assert!(net_io["tx_bytes"].as_u64().unwrap() >= 1024 * 1000);
+    assert!(net_io["tx_packets"].as_u64().unwrap() >= 1000);
+    assert!(interfaces[0]["rx_bandwidth"].as_f64().unwrap() > 0.0);
+    assert!(net_io["rx_drops"].is_u64());
+    assert!(net_io["tcp_out_segs"].as_u64().unwrap() >= 1000);
+    assert!(net_io["tcp_retrans_segs"].is_u64());
+    assert!(net_io["tcp_retransmit_rate"].as_f64().unwrap() <= 1.0);
+    assert!(net_io["udp_in_datagrams"].is_u64());
+
+    env.set("SHUMAI_NET_INTERFACE", "lo,not-an-interface");
+    let measurements = env.measure(1);
+    let net_io = measurements.iter().find(|m| m["name"] == "net_io").unwrap();
+    assert!(net_io["value"].is_null());
+    assert!(net_io["error"]
+        .as_str()
+        .unwrap()
+        .contains("not-an-interface"));
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn system() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_MEASURE", "system");
+    // a busy loop in another process is noise, whatever the number of CPUs
+    env.set("SHUMAI_NOISE_THRESHOLD", "0.001");
+    env.set("SHUMAI_SYSTEM_INTERVAL_MS", "100");
+    let mut hog = std::process::Command::new("sh")
+        .args(["-c", "while :; do :; done"])
+        .spawn()
+        .unwrap();
+    let measurements = env.measure(1);
+    hog.kill().unwrap();
+    hog.wait().unwrap();
+
+    let system = find(&measurements, "system");
+    let noise = system["noise"].as_f64().unwrap();
+    let utilization = system["utilization"].as_f64().unwrap();
+    assert!(noise > 0.001, "{}", system);
+    assert!(utilization >= noise && utilization <= 1.0);
+    assert!(system["benchmark_utilization"].as_f64().unwrap() > 0.0);
+    assert_eq!(system["noisy"], true);
+    assert!(system["throttled"].is_boolean());
+    assert!(system["interrupts"].is_u64());
+    assert!(system["context_switches"].as_u64().unwrap() > 0);
+
+    let cpus = system["cpus"].as_array().unwrap();
+    assert!(!cpus.is_empty());
+    assert!(cpus
+        .iter()
+        .all(|c| c["utilization"].as_f64().unwrap() <= 1.0));
+
+    let samples = find_series(&measurements, "system");
+    assert!(!samples.is_empty());
+    assert_eq!(samples[0]["start_ms"], 0);
+    assert!(samples
+        .windows(2)
+        .all(|w| w[0]["end_ms"] == w[1]["start_ms"]));
+    assert!(samples[0]["value"]["utilization"].is_f64());
+}
+
+const MAX_ENERGY_UJ: u64 = 262_143_328_850;
+
+fn write_zone(root: &std::path::Path, zone: &str, name: &str, energy_uj: &str) {
+    let dir = root.join(zone);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("name"), format!("{}\n", name)).unwrap();
+    std::fs::write(
+        dir.join("max_energy_range_uj"),
+        format!("{}\n", MAX_ENERGY_UJ),
+    )
+    .unwrap();
+    std::fs::write(dir.join("energy_uj"), format!("{}\n", energy_uj)).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn energy() {
+    let mut env = Env::lock();
+    let root = std::env::temp_dir().join(format!("shumai-powercap-test-{}", std::process::id()));
+    env.set("SHUMAI_RAPL_PATH", &root);
+    let measurements = env.measure(1);
+    let energy = measurements.iter().find(|m| m["name"] == "energy").unwrap();
+    assert!(energy["value"].is_null());
+    assert!(energy["error"].as_str().unwrap().contains("unable to read"));
+
+    write_zone(
+        &root,
+        "intel-rapl:0",
+        "package-0",
+        &(MAX_ENERGY_UJ - 1_000_000).to_string(),
+    );
+    write_zone(&root, "intel-rapl:0:0", "core", "0");
+    write_zone(&root, "intel-rapl:0:2", "dram", "not a counter");
+    write_zone(&root, "intel-rapl-mmio:0", "package-0", "0");
+    let measurements = env.measure_with(1, |context| {
+        context.wait_for_start();
+        // the package counter wraps around during the run
+        std::fs::write(root.join("intel-rapl:0/energy_uj"), "2000000\n").unwrap();
+        std::fs::write(root.join("intel-rapl:0:0/energy_uj"), "1500000\n").unwrap();
+        repeat(&context, usize::MAX, || {})
+    });
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let energy = find(&measurements, "energy");
+    assert_eq!(energy["joules"], 3.0, "{}", energy);
+    // 3 joules over a window of at least a second
+    let watts = energy["watts"].as_f64().unwrap();
+    assert!(watts > 0.0 && watts <= 3.0, "{}", watts);
+    assert!(energy["joules_per_op"].as_f64().unwrap() > 0.0);
+
+    let domains = energy["domains"].as_array().unwrap();
+    assert_eq!(domains.len(), 2);
+    assert_eq!(domains[0]["domain"], "package-0");
+    assert_eq!(domains[1]["zone"], "intel-rapl:0:0");
+    assert_eq!(domains[1]["domain"], "package-0/core");
+    assert_eq!(domains[1]["joules"], 1.5);
+
+    let unavailable = energy["unavailable"].as_array().unwrap();
+    assert_eq!(unavailable.len(), 1);
+    assert!(unavailable[0].as_str().unwrap().contains("intel-rapl:0:2"));
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+#[cfg_attr(miri, ignore)]
+fn alloc_counter() {
+    let env = Env::lock();
+    // allocates and frees a 1KB vector per operation
+    let measurements = env.measure_with(1, |context| {
+        context.wait_for_start();
+        let mut i = 0u8;
+        repeat(&context, 100_000, || {
+            drop(std::hint::black_box(vec![i; 1024]));
+            i = i.wrapping_add(1);
+        })
+    });
+    let alloc = find(&measurements, "alloc");
+
+    // tests that don't run a benchmark may still allocate concurrently in this process
+    let allocations = alloc["allocations"].as_u64().unwrap();
+    assert!(allocations >= 100_000, "{}", alloc);
+    assert!(alloc["deallocations"].as_u64().unwrap() >= 100_000);
+    assert!(alloc["bytes_allocated"].as_u64().unwrap() >= 1024 * 100_000);
+    assert!(
+        alloc["peak_live_bytes"].as_u64().unwrap()
+            >= alloc["live_bytes_start"].as_u64().unwrap() + 1024
+    );
+    assert!(alloc["reallocations"].is_u64());
+    assert!(alloc["allocations_per_op"].as_f64().unwrap() >= 1.0);
+    assert!(alloc["bytes_per_op"].as_f64().unwrap() >= 1024.0);
+}
+
+#[test]
+#[cfg(feature = "perf")]
+#[cfg_attr(miri, ignore)]
+fn perf_unavailable_events() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_MEASURE", "perf");
+    env.set("SHUMAI_PERF_EVENTS", "task_clock,not_an_event");
+    let measurements = env.measure(1);
+    let perf = find(&measurements, "perf");
+
+    assert!(perf["counters"]["task_clock"].as_u64().unwrap() > 0);
+    assert!(perf["per_op"]["task_clock"].as_f64().unwrap() > 0.0);
+    assert!(perf["derived"]["ipc"].is_null());
+    assert_eq!(perf["unavailable"]["not_an_event"], "unknown event");
+
+    let group = &perf["groups"][0];
+    assert!(group["raw"]["task_clock"].as_u64().unwrap() > 0);
+    assert!(
+        group["time_enabled_ns"].as_u64().unwrap() >= group["time_running_ns"].as_u64().unwrap()
+    );
+    assert!(perf["multiplexed"].is_boolean());
+}
+
+#[test]
+#[cfg(feature = "perf")]
+#[cfg_attr(miri, ignore)]
+fn perf_per_thread() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_MEASURE", "perf");
+    env.set("SHUMAI_PERF_EVENTS", "task_clock");
+    env.set("SHUMAI_PERF_PER_THREAD", "1");
+    let measurements = env.measure(2);
+    let threads = &find(&measurements, "perf")["threads"];
+
+    let per_thread = threads["per_thread"].as_array().unwrap();
+    assert_eq!(per_thread.len(), 2);
+    let mut sum = 0;
+    for (tid, t) in per_thread.iter().enumerate() {
+        assert_eq!(t["tid"], tid);
+        let task_clock = t["counters"]["task_clock"].as_u64().unwrap();
+        assert!(task_clock > 0);
+        assert!(t["per_op"]["task_clock"].as_f64().unwrap() > 0.0);
+        sum += task_clock;
+    }
+    assert_eq!(threads["total"]["counters"]["task_clock"], sum);
+}
+
+#[test]
+#[cfg(feature = "perf")]
+#[cfg_attr(miri, ignore)]
+fn perf_sample() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_MEASURE", "perf_sample");
+    env.set("SHUMAI_PERF_SAMPLE", "task_clock");
+    let measurements = env.measure(1);
+    let sample = find(&measurements, "perf_sample");
+
+    assert_eq!(sample["event"], "task_clock");
+    assert!(sample["unavailable"].is_null());
+    assert!(sample["samples"].as_u64().unwrap() > 0);
+    let top = sample["top"].as_array().unwrap();
+    assert!(!top.is_empty());
+    // the benchmark loop resolves to named functions rather than raw addresses
+    assert!(top
+        .iter()
+        .any(|f| !f["function"].as_str().unwrap().starts_with("0x")));
+}
+
+#[test]
+#[cfg(feature = "flamegraph")]
+#[cfg_attr(miri, ignore)]
+fn flamegraph_formats() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_MEASURE", "flamegraph");
+    env.set("SHUMAI_FLAMEGRAPH_FORMAT", "svg,folded,pprof");
+    let measurements = env.measure(1);
+    let flamegraph = find(&measurements, "flamegraph");
+
+    for (format, extension) in [("svg", "svg"), ("folded", "folded"), ("pprof", "pb")] {
+        let path = flamegraph[format].as_str().unwrap();
+        assert!(
+            path.ends_with(&format!("-measured-m-1t-iter0.{}", extension)),
+            "{}",
+            path
+        );
+        assert!(std::fs::metadata(path).unwrap().len() > 0);
+    }
+    let folded = std::fs::read_to_string(flamegraph["folded"].as_str().unwrap()).unwrap();
+    assert!(folded
+        .lines()
+        .all(|l| l.rsplit_once(' ').unwrap().1.parse::<usize>().is_ok()));
+}
+
+#[test]
+#[cfg(feature = "flamegraph")]
+#[cfg_attr(miri, ignore)]
+fn flamegraph_aggregate() {
+    let mut env = Env::lock();
+    env.set("SHUMAI_MEASURE", "flamegraph");
+    env.set("SHUMAI_FLAMEGRAPH_FORMAT", "folded");
+    env.set("SHUMAI_FLAMEGRAPH_AGGREGATE", "1");
+    let measurements = env.measure(1);
+    let flamegraph = find(&measurements, "flamegraph");
+
+    // one profile per config, which `diff` refuses to compare per thread count
+    assert_eq!(flamegraph["aggregate"], true);
+    let path = flamegraph["folded"].as_str().unwrap();
+    assert!(path.ends_with("-measured-m.folded"), "{}", path);
+    assert!(std::fs::metadata(path).unwrap().len() > 0);
+}
+
+/// A result file with `iterations` iterations of 2 threads, all of them pointing to the same folded stacks.
+#[cfg(feature = "flamegraph")]
+fn write_result(
+    dir: &std::path::Path,
+    file: &str,
+    name: &str,
+    folded: &str,
+    iterations: usize,
+    aggregate: bool,
+) -> std::path::PathBuf {
+    let folded_path = dir.join(format!("{}.folded", file));
+    std::fs::write(&folded_path, folded).unwrap();
+    let mut value = serde_json::json!({ "folded": folded_path.to_str().unwrap() });
+    if aggregate {
+        value["aggregate"] = true.into();
+    }
+    let iteration = serde_json::json!({
+        "measurements": [{ "name": "flamegraph", "value": value }]
+    });
+    let result = serde_json::json!({
+        "config": { "name": name },
+        "run": [{
+            "thread_cnt": 2,
+            "iterations": vec![iteration; iterations]
+        }]
+    });
+    let path = dir.join(format!("{}.json", file));
+    std::fs::write(&path, result.to_string()).unwrap();
+    path
+}
+
+#[test]
+#[cfg(feature = "flamegraph")]
+fn flamegraph_diff() {
+    use shumai::flamegraph::{diff, DiffError};
+
+    let dir = std::env::temp_dir().join("shumai-flamegraph-diff-test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let baseline = write_result(
+        &dir,
+        "baseline",
+        "foo-a",
+        "main;run;get 10\nmain;run;put 5",
+        1,
+        false,
+    );
+    let candidate = write_result(
+        &dir,
+        "candidate",
+        "foo-a",
+        "main;run;get 3\nmain;run;put 20",
+        1,
+        false,
+    );
+
+    let output = dir.join("diff.svg");
+    diff(&baseline, &candidate, 2, &output).unwrap();
+    let svg = std::fs::read_to_string(&output).unwrap();
+    assert!(svg.contains("<svg"));
+    assert!(svg.contains("put"));
+
+    let err = diff(&baseline, &candidate, 4, &output).unwrap_err();
+    assert!(matches!(
+        err,
+        DiffError::MissingProfile { thread_cnt: 4, .. }
+    ));
+
+    let other = write_result(&dir, "other", "foo-b", "main;run;get 1", 1, false);
+    let err = diff(&baseline, &other, 2, &output).unwrap_err();
+    assert!(matches!(err, DiffError::ConfigMismatch { .. }));
+
+    // iterations sharing a profile count it once
+    let repeated = write_result(
+        &dir,
+        "repeated",
+        "foo-a",
+        "main;run;get 3\nmain;run;put 20",
+        3,
+        false,
+    );
+    let repeated_output = dir.join("repeated.svg");
+    diff(&baseline, &repeated, 2, &repeated_output).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&repeated_output).unwrap(),
+        std::fs::read_to_string(&output).unwrap()
+    );
+
+    let aggregated = write_result(
+        &dir,
+        "aggregated",
+        "foo-a",
+        "main;run;get 3\nmain;run;put 20",
+        3,
+        true,
+    );
+    let err = diff(&baseline, &aggregated, 2, &output).unwrap_err();
+    assert!(matches!(err, DiffError::Aggregated { .. }));
+}
+
+/// Answers every request with `body`, like pcm-sensor-server's `/persecond` endpoint.
+#[cfg(feature = "pcm")]
+fn mock_pcm_server(body: &'static str) -> String {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+    format!("http://{}/persecond", addr)
+}
+
+#[test]
+#[cfg(feature = "pcm")]
+#[cfg_attr(miri, ignore)]
+fn pcm_mock_server() {
+    let endpoint = mock_pcm_server(
+        r#"{
+            "Sockets": [
+                {
+                    "Socket ID": 0,
+                    "Core Aggregate": { "Core Counters": { "L3 Cache Hits": 90, "L3 Cache Misses": 10 } },
+                    "Uncore": { "Uncore Counters": { "DRAM Reads": 1000, "DRAM Writes": 500, "Persistent Memory Reads": 7, "Persistent Memory Writes": 3 } }
+                },
+                {
+                    "Socket ID": 1,
+                    "Core Aggregate": { "Core Counters": { "L3 Cache Hits": 60, "L3 Cache Misses": 40 } },
+                    "Uncore": { "Uncore Counters": { "DRAM Reads": 2000, "DRAM Writes": 100, "Persistent Memory Reads": 0, "Persistent Memory Writes": 0 } }
+                }
+            ],
+            "QPI/UPI Links": {
+                "QPI Counters Socket 0": {
+                    "Utilization Incoming Data Traffic On Link 0": 0.1,
+                    "Utilization Incoming Data Traffic On Link 1": 0.3,
+                    "Utilization Outgoing Data And Non-Data Traffic On Link 0": 0.2,
+                    "Utilization Outgoing Data And Non-Data Traffic On Link 1": 0.4
+                },
+                "QPI Counters Socket 1": {
+                    "Utilization Incoming Data Traffic On Link 0": 0.5,
+                    "Utilization Incoming Data Traffic On Link 1": 0.7,
+                    "Utilization Outgoing Data And Non-Data Traffic On Link 0": 0.6,
+                    "Utilization Outgoing Data And Non-Data Traffic On Link 1": 0.8
+                }
+            }
+        }"#,
+    );
+    let mut env = Env::lock();
+    env.set("SHUMAI_MEASURE", "pcm");
+    env.set("SHUMAI_PCM_ENDPOINT", &endpoint);
+    env.set("SHUMAI_PCM_INTERVAL_MS", "100");
+
+    let measurements = env.measure(1);
+    let samples = find_series(&measurements, "pcm");
+    assert!(!samples.is_empty());
+    assert_eq!(samples[0]["start_ms"], 0);
+    assert!(samples[0]["end_ms"].as_u64().unwrap() >= 100);
+    for pair in samples.windows(2) {
+        assert_eq!(pair[0]["end_ms"], pair[1]["start_ms"]);
+    }
+    let sample = &samples[0]["value"];
+    assert_eq!(sample["sockets"].as_array().unwrap().len(), 2);
+    let socket1 = &sample["sockets"][1];
+    assert_eq!(socket1["socket"], 1);
+    assert_eq!(socket1["dram_read"], 2000);
+    assert_eq!(socket1["upi_links"][1]["link"], 1);
+    assert_eq!(socket1["upi_links"][1]["out_util"], 0.8);
+    assert_eq!(sample["total"]["dram_read"], 3000);
+    assert_eq!(sample["total"]["l3_miss"], 50);
+    assert!((sample["total"]["upi_in_util"].as_f64().unwrap() - 0.4).abs() < 1e-9);
+
+    // every sample reports the same rates, so the averages are the rates
+    let summary = find(&measurements, "pcm");
+    let duration_ms = summary["duration_ms"].as_u64().unwrap();
+    assert_eq!(duration_ms, samples.last().unwrap()["end_ms"]);
+    assert!((summary["dram_read_avg"].as_f64().unwrap() - 3000.0).abs() < 1e-6);
+    let expected_total = 3000.0 * duration_ms as f64 / 1000.0;
+    assert!((summary["dram_read_total"].as_f64().unwrap() - expected_total).abs() < 1e-6);
+    assert!((summary["l3_hit_ratio"].as_f64().unwrap() - 0.75).abs() < 1e-9);
+
+    // pcm reports its last full second, the samples only cover full intervals
+    env.set("SHUMAI_PCM_INTERVAL_MS", "300");
+    let measurements = env.measure(1);
+    let samples = find_series(&measurements, "pcm");
+    assert!(!samples.is_empty());
+    for s in samples {
+        assert!(s["end_ms"].as_u64().unwrap() - s["start_ms"].as_u64().unwrap() >= 300);
+    }
+
+    env.set("SHUMAI_PCM_SOCKETS", "1");
+    let measurements = env.measure(1);
+    let sample = &find_series(&measurements, "pcm")[0]["value"];
+    assert_eq!(sample["sockets"].as_array().unwrap().len(), 1);
+    assert_eq!(sample["total"]["dram_read"], 2000);
+
+    // nothing listens on the port once the listener is dropped
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    env.set("SHUMAI_PCM_ENDPOINT", format!("http://127.0.0.1:{}/", port));
+    let measurements = env.measure(1);
+    let pcm = measurements.iter().find(|m| m["name"] == "pcm").unwrap();
+    assert!(pcm.get("series").is_none());
+    assert!(pcm["error"].as_str().unwrap().contains("pcm-sensor-server"));
+}
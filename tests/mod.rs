@@ -172,6 +172,22 @@ fn check_load_cleanup_result() {
     }
 }
 
+#[test]
+fn run_loaded() {
+    let config = Foo::load().expect("Failed to parse config!");
+
+    let mut benchmark = TestBench::default();
+    let result = shumai::run_loaded(&mut benchmark, &config[0], config.record(), 1);
+    assert_eq!(result.source.as_ref(), config.source());
+    assert!(result.source.is_some());
+    assert!(result.overrides.is_empty());
+
+    // a config that was not loaded has no source
+    let mut benchmark = TestBench::default();
+    let result = shumai::run(&mut benchmark, &config[0], 1);
+    assert!(result.source.is_none());
+}
+
 #[test]
 #[cfg_attr(miri, ignore)]
 fn write_json() {
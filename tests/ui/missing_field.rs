@@ -0,0 +1,9 @@
+use shumai::config;
+
+#[config(path = "benchmark.toml")]
+pub struct Foo {
+    pub name: String,
+    pub threads: Vec<usize>,
+}
+
+fn main() {}
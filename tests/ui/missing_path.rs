@@ -0,0 +1,17 @@
+use shumai::config;
+
+#[config]
+pub struct Foo {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+}
+
+#[config(file = "benchmark.toml")]
+pub struct Bar {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use shumai::config;
+
+#[config(section)]
+pub struct Storage {
+    #[matrix]
+    pub cache_mb: usize,
+}
+
+#[config(path = "benchmark.toml")]
+pub struct Foo {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+    #[matrix]
+    #[nested]
+    pub storage: Storage,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use shumai::config;
+
+#[config(path = "benchmark.toml")]
+pub enum Foo {
+    A,
+    B,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use shumai::config;
+
+#[config(path = "benchmark.toml")]
+pub struct Foo {
+    pub name: std::string::String,
+    pub threads: std::vec::Vec<usize>,
+    pub time: ::core::primitive::usize,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use shumai::config;
+
+#[config(path = "benchmark.toml")]
+#[derive(PartialEq, Eq, Hash)]
+pub struct Foo {
+    pub name: String,
+    pub threads: Vec<usize>,
+    pub time: usize,
+}
+
+fn main() {
+    let foo = Foo {
+        name: "foo".to_string(),
+        threads: vec![1],
+        time: 1,
+    };
+    assert!(foo == foo.clone());
+}
@@ -0,0 +1,11 @@
+use shumai::config;
+
+#[config(path = "benchmark.toml")]
+pub struct Foo {
+    pub name: String,
+    #[matrix]
+    pub threads: Vec<usize>,
+    pub time: usize,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use shumai::config;
+
+#[config(path = "benchmark.toml")]
+pub struct Foo(String, Vec<usize>, usize);
+
+fn main() {}
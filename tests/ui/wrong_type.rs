@@ -0,0 +1,10 @@
+use shumai::config;
+
+#[config(path = "benchmark.toml")]
+pub struct Foo {
+    pub name: &'static str,
+    pub threads: usize,
+    pub time: u64,
+}
+
+fn main() {}